@@ -13,6 +13,46 @@ use syn::{
 ///
 /// Field types are mapped to PostgreSQL types automatically for common types.
 /// For unknown types, annotate the field with `#[pg(TYPE)]`, e.g. `#[pg(TIMESTAMPTZ)]`.
+///
+/// Any `serde::Serialize` field can be stored as JSON with `#[batch_copy(json)]`
+/// or `#[batch_copy(jsonb)]` (requires the `serde` feature of `batch-copy`).
+///
+/// `Decimal` and `BigDecimal` fields map to NUMERIC; declare the column's precision
+/// and scale for the generated DDL with `#[batch_copy(numeric(18, 6))]`.
+///
+/// A field whose type also derives `BatchCopy` can be marked `#[batch_copy(flatten)]`
+/// to splice its columns into this row in place of the field. Its type cannot use
+/// the struct's generic parameters.
+///
+/// The generated DDL takes column constraints from `#[batch_copy(primary_key)]`,
+/// `unique`, `default = "now()"`, `check = "price > 0"` and `index` (or
+/// `index = "brin"` for another method), and turns doc comments into
+/// `COMMENT ON COLUMN`. Flattened fields contribute their column definitions and
+/// primary key columns, but not their indexes or comments.
+///
+/// For upserts, `#[batch_copy(conflict_key)]` marks the conflict target (the
+/// primary key by default) and `#[batch_copy(no_update)]` keeps a column's
+/// existing value when a row conflicts. `#[batch_copy(replace_key)]` fields pick
+/// the rows a replace batch deletes.
+///
+/// `#[batch_copy(partition_by = "range(dt)")]` declares the table partitioned by
+/// `dt`, and `#[batch_copy(hypertable = "dt")]` turns it into a TimescaleDB hypertable.
+///
+/// `#[batch_copy(shard_key)]` marks the field whose value picks a `ShardedCopier`'s
+/// database.
+///
+/// `#[batch_copy(table_fn = "tenant_table")]` copies each row to the table returned
+/// by `tenant_table(&row)` (a `String` or `&str`), which must have the same columns
+/// as `table`.
+///
+/// `#[batch_copy(format = "csv")]` (or `"text"`) makes the row COPY in that format
+/// instead of binary, unless `Configuration::copy_format` says otherwise.
+///
+/// On an enum whose variants each wrap one `BatchCopy` row, e.g. `Click(ClickRow)`,
+/// it derives `BatchCopyTables` instead, for use with `MultiCopier`.
+///
+/// Lifetime and type parameters are carried over to the impl. Fields of a generic
+/// type need `#[pg(TYPE)]` and gain a `ToSql + Sync` bound.
 #[proc_macro_derive(BatchCopy, attributes(batch_copy, pg))]
 pub fn derive_batch_copy(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -24,8 +64,14 @@ pub fn derive_batch_copy(input: TokenStream) -> TokenStream {
 
 fn derive_impl(input: DeriveInput) -> syn::Result<TokenStream2> {
     let name = &input.ident;
+    let type_params: Vec<&Ident> = input.generics.type_params().map(|p| &p.ident).collect();
+
+    if let Data::Enum(e) = &input.data {
+        return derive_tables_impl(&input, e);
+    }
 
-    let table_name = extract_table_name(&input)?;
+    let struct_attrs = parse_struct_attrs(&input)?;
+    let table_name = &struct_attrs.table;
 
     let fields = match &input.data {
         Data::Struct(s) => match &s.fields {
@@ -40,95 +86,590 @@ fn derive_impl(input: DeriveInput) -> syn::Result<TokenStream2> {
         _ => {
             return Err(syn::Error::new_spanned(
                 name,
-                "BatchCopy can only be derived for structs",
+                "BatchCopy can only be derived for structs and enums",
             ))
         }
     };
 
-    let field_names: Vec<String> = fields
+    let field_attrs: Vec<FieldAttrs> = fields
         .iter()
-        .map(|f| f.ident.as_ref().unwrap().to_string())
-        .collect();
+        .map(parse_field_attrs)
+        .collect::<syn::Result<_>>()?;
 
-    let columns = field_names.join(", ");
-    let check_stmt = format!("SELECT {} FROM {} LIMIT 0", columns, table_name);
-    let copy_stmt = format!(
-        "COPY {} ({}) FROM STDIN (FORMAT binary)",
-        table_name, columns
-    );
+    // Each field contributes its own column or, when flattened, all the
+    // columns of a nested row
+    let mut column_list = ConstStr::default();
+    let mut column_defs = ConstStr::default();
+    let mut type_segments: Vec<Segment> = vec![];
+    let mut column_segments: Vec<Segment> = vec![];
+    let mut pushes: Vec<TokenStream2> = vec![];
+    let mut bounds: Vec<TokenStream2> = vec![];
+    // `, col` for each primary key column, nested ones included
+    let mut primary_key = ConstStr::default();
+    let mut flattened = false;
+    let mut table_extras: Vec<String> = vec![];
+    let mut comments: Vec<String> = vec![];
+    let mut shard_column: Option<String> = None;
+    for (i, (field, attrs)) in fields.iter().zip(&field_attrs).enumerate() {
+        if i > 0 {
+            column_list.push_lit(", ");
+            column_defs.push_lit(",\n");
+        }
 
-    let pg_types: Vec<TokenStream2> = fields
-        .iter()
-        .map(field_pg_type)
-        .collect::<syn::Result<_>>()?;
+        let id = field.ident.as_ref().unwrap();
+        if attrs.flatten {
+            let ty = &field.ty;
+            // the columns are spliced in by constants, which cannot be generic
+            if mentions_generic_param(ty, &input.generics) {
+                return Err(syn::Error::new_spanned(
+                    ty,
+                    "a flattened field cannot use the struct's generic parameters",
+                ));
+            }
+            column_list.push_expr(
+                quote! { <#ty as ::batch_copy::__private::ColumnGroup>::COLUMN_LIST },
+            );
+            column_defs.push_expr(
+                quote! { <#ty as ::batch_copy::__private::ColumnGroup>::COLUMN_DEFS },
+            );
+            primary_key.push_expr(
+                quote! { <#ty as ::batch_copy::__private::ColumnGroup>::PRIMARY_KEY },
+            );
+            flattened = true;
+            type_segments.push(Segment::Flattened(ty));
+            column_segments.push(Segment::Flattened(ty));
+            pushes.push(quote! { ::batch_copy::BatchCopyRow::fill_copy_refs(&self.#id, out); });
+            continue;
+        }
 
-    let field_idents: Vec<&Ident> = fields
-        .iter()
-        .map(|f| f.ident.as_ref().unwrap())
-        .collect();
+        let col = id.to_string();
+        if attrs.shard_key {
+            if shard_column.is_some() {
+                return Err(syn::Error::new_spanned(
+                    id,
+                    "only one field can be the shard_key",
+                ));
+            }
+            shard_column = Some(col.clone());
+        }
+        let (ddl_type, nullable) = field_ddl_info(field, attrs)?;
+        column_list.push_lit(&col);
+        let mut def = format!("    {col} {ddl_type}");
+        if !nullable {
+            def.push_str(" NOT NULL");
+        }
+        if let Some(default) = &attrs.default {
+            def.push_str(&format!(" DEFAULT {default}"));
+        }
+        if attrs.unique {
+            def.push_str(" UNIQUE");
+        }
+        if let Some(check) = &attrs.check {
+            def.push_str(&format!(" CHECK ({check})"));
+        }
+        column_defs.push_lit(&def);
 
-    let pushes = field_idents.iter().map(|id| {
-        quote! { out.push(&self.#id as &(dyn ::batch_copy::__private::ToSql + Sync)); }
-    });
+        if attrs.primary_key {
+            primary_key.push_lit(&format!(", {col}"));
+        }
+        if let Some(method) = &attrs.index {
+            let using = match method {
+                Some(method) => format!(" USING {method}"),
+                None => String::new(),
+            };
+            let index_name = format!("{}_{col}_idx", table_name.replace('.', "_"));
+            table_extras.push(format!(
+                "CREATE INDEX IF NOT EXISTS {index_name} ON {table_name}{using} ({col});"
+            ));
+        }
+        if let Some(doc) = doc_comment(field) {
+            comments.push(format!(
+                "COMMENT ON COLUMN {table_name}.{col} IS '{}';",
+                doc.replace('\'', "''")
+            ));
+        }
 
-    let ddl_infos: Vec<(String, bool)> = fields
-        .iter()
-        .map(field_ddl_info)
-        .collect::<syn::Result<_>>()?;
+        // A field of generic type is written as-is and must be ToSql
+        if attrs.json.is_none() && mentions_type_param(&field.ty, &type_params) {
+            let ty = &field.ty;
+            bounds.push(quote! { #ty: ::batch_copy::__private::ToSql + Sync });
+        }
 
-    let col_defs: Vec<String> = field_names
-        .iter()
-        .zip(&ddl_infos)
-        .map(|(col, (ddl_type, nullable))| {
-            if *nullable {
-                format!("    {col} {ddl_type}")
+        let primary_key = attrs.primary_key;
+        let conflict_key = attrs.conflict_key;
+        let replace_key = attrs.replace_key;
+        let update = !attrs.no_update;
+        let pg_type = field_pg_type(field, attrs)?;
+        Segment::push_item(&mut type_segments, pg_type);
+        Segment::push_item(
+            &mut column_segments,
+            quote! {
+                ::batch_copy::Column {
+                    name: #col,
+                    sql_type: #ddl_type,
+                    nullable: #nullable,
+                    primary_key: #primary_key,
+                    conflict_key: #conflict_key,
+                    replace_key: #replace_key,
+                    update: #update,
+                }
+            },
+        );
+        pushes.push(field_push(field, attrs));
+    }
+
+    let mut check_stmt = ConstStr::default();
+    check_stmt.push_lit("SELECT ");
+    check_stmt.extend(&column_list);
+    check_stmt.push_lit(&format!(" FROM {} LIMIT 0", table_name));
+
+    let mut copy_stmt = ConstStr::default();
+    copy_stmt.push_lit(&format!("COPY {} (", table_name));
+    copy_stmt.extend(&column_list);
+    copy_stmt.push_lit(&format!(") FROM STDIN (FORMAT {})", struct_attrs.format));
+
+    let mut ddl_stmt = ConstStr::default();
+    ddl_stmt.push_lit(&format!("CREATE TABLE {} (\n", table_name));
+    ddl_stmt.extend(&column_defs);
+    if flattened {
+        // whether there is a key at all is only known once the nested ones are
+        ddl_stmt.push_expr(quote! {
+            if #primary_key.is_empty() {
+                ""
             } else {
-                format!("    {col} {ddl_type} NOT NULL")
+                ::batch_copy::__private::concatcp!(
+                    ",\n    PRIMARY KEY (",
+                    ::batch_copy::__private::strip_list_sep(#primary_key),
+                    ")"
+                )
             }
-        })
-        .collect();
+        });
+    } else if let [StrPart::Lit(keys)] = primary_key.parts.as_slice() {
+        ddl_stmt.push_lit(&format!(",\n    PRIMARY KEY ({})", &keys[2..]));
+    }
+    // Postgres only accepts a primary key that includes every partition column;
+    // the keys of flattened fields are only known to the compiler
+    let mut key_checks = vec![];
+    let partition_by = struct_attrs.partition_by.as_ref();
+    if let Some((_, columns)) = partition_by.filter(|_| !primary_key.parts.is_empty()) {
+        let own_keys: Vec<&str> = primary_key
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                StrPart::Lit(lit) => Some(lit.split(", ").skip(1)),
+                StrPart::Expr(_) => None,
+            })
+            .flatten()
+            .collect();
+        for column in columns.split(',').map(str::trim) {
+            if own_keys.contains(&column) {
+                continue;
+            }
+            let (message, check) = if column.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                (
+                    format!("the primary key of a partitioned table must include `{column}`"),
+                    quote! { ::batch_copy::__private::has_list_item(#primary_key, #column) },
+                )
+            } else {
+                (
+                    "a table with a primary key can only be partitioned by columns".to_string(),
+                    quote! { false },
+                )
+            };
+            if !flattened {
+                return Err(syn::Error::new_spanned(name, message));
+            }
+            key_checks.push(quote! {
+                assert!(#primary_key.is_empty() || #check, #message);
+            });
+        }
+    }
+    let key_checks =
+        (!key_checks.is_empty()).then(|| quote! { const _: () = { #(#key_checks)* }; });
+
+    match &struct_attrs.partition_by {
+        Some((method, columns)) => {
+            ddl_stmt.push_lit(&format!("\n) PARTITION BY {method} ({columns});"))
+        }
+        None => ddl_stmt.push_lit("\n);"),
+    }
+    if let Some(column) = &struct_attrs.hypertable {
+        ddl_stmt.push_lit(&format!(
+            "\nSELECT create_hypertable('{table_name}', '{column}', if_not_exists => TRUE);"
+        ));
+    }
+    for stmt in table_extras.iter().chain(&comments) {
+        ddl_stmt.push_lit(&format!("\n{stmt}"));
+    }
+
+    // Only a single-column range key can have partitions created for it
+    let partition_column = match &struct_attrs.partition_by {
+        Some((method, columns))
+            if method == "RANGE"
+                && columns.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+        {
+            quote! { ::std::option::Option::Some(#columns) }
+        }
+        _ => quote! { ::std::option::Option::None },
+    };
+
+    let shard_column = match &shard_column {
+        Some(col) => quote! { ::std::option::Option::Some(#col) },
+        None => quote! { ::std::option::Option::None },
+    };
+
+    let table_fn = struct_attrs.table_fn.as_ref().map(|path| {
+        quote! {
+            const DYNAMIC_TABLE: bool = true;
+            fn table(&self) -> ::std::borrow::Cow<'_, str> {
+                ::std::borrow::Cow::from(#path(self))
+            }
+        }
+    });
+
+    let copy_format = match struct_attrs.format.as_str() {
+        "text" => Some(quote! { Text }),
+        "csv" => Some(quote! { Csv }),
+        _ => None,
+    }
+    .map(|variant| {
+        quote! {
+            const COPY_FORMAT: ::batch_copy::CopyFormat = ::batch_copy::CopyFormat::#variant;
+        }
+    });
 
-    let ddl_stmt = format!(
-        "CREATE TABLE {} (\n{}\n);",
-        table_name,
-        col_defs.join(",\n")
+    let mut generics = input.generics.clone();
+    generics.make_where_clause().predicates.extend(
+        bounds
+            .into_iter()
+            .map(syn::parse2::<syn::WherePredicate>)
+            .collect::<syn::Result<Vec<_>>>()?,
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let impl_for_row = |tr: TokenStream2, items: TokenStream2| {
+        quote! {
+            impl #impl_generics #tr for #name #ty_generics #where_clause {
+                #items
+            }
+        }
+    };
+    let (types, type_parts) = concat_tokens(
+        &type_segments,
+        quote! { ::batch_copy::__private::Type },
+        quote! { TYPES },
+        impl_for_row,
+    );
+    let (column_infos, column_parts) = concat_tokens(
+        &column_segments,
+        quote! { ::batch_copy::Column },
+        quote! { COLUMNS },
+        impl_for_row,
     );
 
     Ok(quote! {
-        impl ::batch_copy::BatchCopyRow for #name {
+        impl #impl_generics ::batch_copy::BatchCopyRow for #name #ty_generics #where_clause {
+            const TABLE: &'static str = #table_name;
+            const PARTITION_COLUMN: ::std::option::Option<&'static str> = #partition_column;
+            const SHARD_COLUMN: ::std::option::Option<&'static str> = #shard_column;
             const CHECK_STATEMENT: &'static str = #check_stmt;
             const COPY_STATEMENT: &'static str = #copy_stmt;
             const DDL_STATEMENT: &'static str = #ddl_stmt;
-            const TYPES: &'static [::batch_copy::__private::Type] = &[
-                #(#pg_types),*
-            ];
-            fn fill_copy_refs<'a>(&'a self, out: &mut ::std::vec::Vec<&'a (dyn ::batch_copy::__private::ToSql + Sync)>) {
+            #copy_format
+            const TYPES: &'static [::batch_copy::__private::Type] = #types;
+            const COLUMNS: &'static [::batch_copy::Column] = #column_infos;
+            fn fill_copy_refs<'__row>(&'__row self, out: &mut ::std::vec::Vec<&'__row (dyn ::batch_copy::__private::ToSql + Sync)>) {
                 #(#pushes)*
             }
+            #table_fn
+        }
+
+        impl #impl_generics ::batch_copy::__private::ColumnGroup for #name #ty_generics #where_clause {
+            const COLUMN_LIST: &'static str = #column_list;
+            const COLUMN_DEFS: &'static str = #column_defs;
+            const PRIMARY_KEY: &'static str = #primary_key;
+        }
+
+        #type_parts
+        #column_parts
+
+        #key_checks
+    })
+}
+
+/// `BatchCopyTables` for an enum whose variants each wrap one row type
+fn derive_tables_impl(input: &DeriveInput, data: &syn::DataEnum) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let mut schemas = vec![];
+    let mut index_arms = vec![];
+    let mut encode_arms = vec![];
+    for (i, variant) in data.variants.iter().enumerate() {
+        let ty = match &variant.fields {
+            Fields::Unnamed(f) if f.unnamed.len() == 1 => &f.unnamed[0].ty,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "each variant must wrap a single BatchCopy row, e.g. `Click(ClickRow)`",
+                ))
+            }
+        };
+        let ident = &variant.ident;
+        schemas.push(quote! { ::batch_copy::TableSchema::of::<#ty>() });
+        index_arms.push(quote! { Self::#ident(_) => #i, });
+        encode_arms
+            .push(quote! { Self::#ident(row) => ::batch_copy::__private::encode_row(row, buf), });
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::batch_copy::BatchCopyTables for #name #ty_generics #where_clause {
+            const TABLES: &'static [::batch_copy::TableSchema] = &[#(#schemas),*];
+            fn table_index(&self) -> usize {
+                match self {
+                    #(#index_arms)*
+                }
+            }
+            fn encode(&self, buf: &mut ::batch_copy::__private::BytesMut) -> ::std::result::Result<(), ::std::boxed::Box<dyn ::std::error::Error + Sync + Send>> {
+                match self {
+                    #(#encode_arms)*
+                }
+            }
         }
     })
 }
 
-fn extract_table_name(input: &DeriveInput) -> syn::Result<String> {
+/// Whether a type refers to any of the struct's type parameters
+fn mentions_type_param(ty: &Type, params: &[&Ident]) -> bool {
+    match ty {
+        Type::Path(tp) => tp.path.segments.iter().any(|seg| {
+            params.iter().any(|p| seg.ident == **p)
+                || match &seg.arguments {
+                    PathArguments::AngleBracketed(ab) => ab.args.iter().any(|arg| match arg {
+                        GenericArgument::Type(inner) => mentions_type_param(inner, params),
+                        _ => false,
+                    }),
+                    _ => false,
+                }
+        }),
+        Type::Reference(tr) => mentions_type_param(&tr.elem, params),
+        Type::Slice(ts) => mentions_type_param(&ts.elem, params),
+        Type::Array(ta) => mentions_type_param(&ta.elem, params),
+        Type::Tuple(tt) => tt.elems.iter().any(|t| mentions_type_param(t, params)),
+        _ => false,
+    }
+}
+
+/// Whether `ty` names any type, const or lifetime parameter of `generics`
+fn mentions_generic_param(ty: &Type, generics: &syn::Generics) -> bool {
+    fn scan(tokens: TokenStream2, generics: &syn::Generics) -> bool {
+        let mut lifetime = false;
+        tokens.into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Group(group) => scan(group.stream(), generics),
+            proc_macro2::TokenTree::Punct(punct) => {
+                lifetime = punct.as_char() == '\'';
+                false
+            }
+            proc_macro2::TokenTree::Ident(ident) if std::mem::take(&mut lifetime) => {
+                generics.lifetimes().any(|p| p.lifetime.ident == ident)
+            }
+            proc_macro2::TokenTree::Ident(ident) => {
+                generics.type_params().any(|p| p.ident == ident)
+                    || generics.const_params().any(|p| p.ident == ident)
+            }
+            proc_macro2::TokenTree::Literal(_) => false,
+        })
+    }
+    scan(quote! { #ty }, generics)
+}
+
+/// A `&'static str` assembled from literals and the consts of other types.
+/// Renders as a plain literal unless a const is involved, then as `concatcp!`.
+#[derive(Default, Clone)]
+struct ConstStr {
+    parts: Vec<StrPart>,
+}
+
+#[derive(Clone)]
+enum StrPart {
+    Lit(String),
+    Expr(TokenStream2),
+}
+
+impl ConstStr {
+    fn push_lit(&mut self, lit: &str) {
+        match self.parts.last_mut() {
+            Some(StrPart::Lit(last)) => last.push_str(lit),
+            _ => self.parts.push(StrPart::Lit(lit.to_string())),
+        }
+    }
+
+    fn push_expr(&mut self, expr: TokenStream2) {
+        self.parts.push(StrPart::Expr(expr));
+    }
+
+    fn extend(&mut self, other: &ConstStr) {
+        for part in &other.parts {
+            match part {
+                StrPart::Lit(lit) => self.push_lit(lit),
+                StrPart::Expr(expr) => self.push_expr(expr.clone()),
+            }
+        }
+    }
+}
+
+impl quote::ToTokens for ConstStr {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        match self.parts.as_slice() {
+            [] => return "".to_tokens(tokens),
+            [StrPart::Lit(lit)] => return lit.to_tokens(tokens),
+            _ => {}
+        }
+        let parts = self.parts.iter().map(|part| match part {
+            StrPart::Lit(lit) => quote! { #lit },
+            StrPart::Expr(expr) => expr.clone(),
+        });
+        quote! { ::batch_copy::__private::concatcp!(#(#parts),*) }.to_tokens(tokens)
+    }
+}
+
+/// Consecutive plain columns, or the columns of a flattened field
+enum Segment<'a> {
+    Columns(Vec<TokenStream2>),
+    Flattened(&'a Type),
+}
+
+impl Segment<'_> {
+    fn push_item(segments: &mut Vec<Self>, item: TokenStream2) {
+        match segments.last_mut() {
+            Some(Segment::Columns(items)) => items.push(item),
+            _ => segments.push(Segment::Columns(vec![item])),
+        }
+    }
+}
+
+/// A `&'static [#elem]` holding the plain items in order, spliced with the
+/// `BatchCopyRow::#konst` slice of each flattened field, and the `ConcatParts`
+/// impl the splicing needs
+fn concat_tokens(
+    segments: &[Segment],
+    elem: TokenStream2,
+    konst: TokenStream2,
+    impl_for_row: impl Fn(TokenStream2, TokenStream2) -> TokenStream2,
+) -> (TokenStream2, Option<TokenStream2>) {
+    match segments {
+        [] => (quote! { &[] }, None),
+        [Segment::Columns(items)] => (quote! { &[#(#items),*] }, None),
+        _ => {
+            let mut lens = vec![];
+            let mut slices = vec![];
+            for segment in segments {
+                match segment {
+                    Segment::Columns(items) => {
+                        let n = items.len();
+                        lens.push(quote! { #n });
+                        slices.push(quote! { &[#(#items),*] });
+                    }
+                    Segment::Flattened(ty) => {
+                        lens.push(quote! { <#ty as ::batch_copy::BatchCopyRow>::#konst.len() });
+                        slices.push(quote! { <#ty as ::batch_copy::BatchCopyRow>::#konst });
+                    }
+                }
+            }
+            let parts = impl_for_row(
+                quote! { ::batch_copy::__private::ConcatParts<#elem> },
+                quote! { const PARTS: &'static [&'static [#elem]] = &[#(#slices),*]; },
+            );
+            let concat = quote! {
+                &::batch_copy::__private::Concat::<#elem, Self, { #(#lens)+* }>::ARRAY
+            };
+            (concat, Some(parts))
+        }
+    }
+}
+
+/// Options set on the struct with `#[batch_copy(...)]`
+struct StructAttrs {
+    table: String,
+    /// partitioning method and key columns from `partition_by = "range(dt)"`
+    partition_by: Option<(String, String)>,
+    /// time column of a TimescaleDB hypertable
+    hypertable: Option<String>,
+    /// function choosing each row's table, from `table_fn = "path"`
+    table_fn: Option<syn::Path>,
+    /// COPY format from `format = "csv"`, `binary` by default
+    format: String,
+}
+
+fn parse_struct_attrs(input: &DeriveInput) -> syn::Result<StructAttrs> {
+    let mut out = StructAttrs {
+        table: to_snake_case(&input.ident.to_string()),
+        partition_by: None,
+        hypertable: None,
+        table_fn: None,
+        format: "binary".to_string(),
+    };
     for attr in &input.attrs {
         if attr.path().is_ident("batch_copy") {
-            let nested =
-                attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+            let nested = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
             for meta in nested {
-                if let Meta::NameValue(nv) = meta {
-                    if nv.path.is_ident("table") {
-                        if let Expr::Lit(ExprLit {
-                            lit: Lit::Str(s), ..
-                        }) = nv.value
-                        {
-                            return Ok(s.value());
+                let Meta::NameValue(nv) = meta else {
+                    return Err(syn::Error::new_spanned(
+                        meta,
+                        "unknown batch_copy attribute, expected `key = \"value\"`",
+                    ));
+                };
+                if nv.path.is_ident("table") {
+                    out.table = lit_str(&nv.value)?;
+                } else if nv.path.is_ident("partition_by") {
+                    let spec = lit_str(&nv.value)?;
+                    let parsed = spec
+                        .split_once('(')
+                        .and_then(|(method, rest)| Some((method, rest.strip_suffix(')')?)))
+                        .filter(|(method, _)| !method.trim().is_empty());
+                    match parsed {
+                        Some((method, columns)) => {
+                            out.partition_by =
+                                Some((method.trim().to_uppercase(), columns.trim().to_string()))
                         }
+                        None => {
+                            return Err(syn::Error::new_spanned(
+                                &nv.value,
+                                "expected a partitioning method and key, e.g. \"range(dt)\"",
+                            ))
+                        }
+                    }
+                } else if nv.path.is_ident("hypertable") {
+                    out.hypertable = Some(lit_str(&nv.value)?);
+                } else if nv.path.is_ident("table_fn") {
+                    let path = lit_str(&nv.value)?;
+                    out.table_fn = Some(syn::parse_str(&path).map_err(|_| {
+                        syn::Error::new_spanned(&nv.value, "expected a function path")
+                    })?);
+                } else if nv.path.is_ident("format") {
+                    let format = lit_str(&nv.value)?.to_lowercase();
+                    if !matches!(format.as_str(), "binary" | "text" | "csv") {
+                        return Err(syn::Error::new_spanned(
+                            &nv.value,
+                            "expected \"binary\", \"text\" or \"csv\"",
+                        ));
                     }
+                    out.format = format;
+                } else {
+                    return Err(syn::Error::new_spanned(
+                        &nv.path,
+                        "unknown batch_copy attribute",
+                    ));
                 }
             }
         }
     }
-    Ok(to_snake_case(&input.ident.to_string()))
+    if out.partition_by.is_some() && out.hypertable.is_some() {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "a table cannot use both partition_by and hypertable",
+        ));
+    }
+    Ok(out)
 }
 
 fn to_snake_case(s: &str) -> String {
@@ -142,7 +683,257 @@ fn to_snake_case(s: &str) -> String {
     out
 }
 
-fn field_pg_type(field: &syn::Field) -> syn::Result<TokenStream2> {
+/// Options set on a field with `#[batch_copy(...)]`
+#[derive(Default)]
+struct FieldAttrs {
+    json: Option<JsonKind>,
+    /// precision and optional scale from `numeric(p, s)`
+    numeric: Option<(u32, Option<u32>)>,
+    /// splice in the columns of a nested `BatchCopy` struct
+    flatten: bool,
+    primary_key: bool,
+    /// part of the upsert conflict target
+    conflict_key: bool,
+    /// keep the existing value on upsert
+    no_update: bool,
+    /// identifies the rows a replace batch deletes
+    replace_key: bool,
+    /// picks the database of a `ShardedCopier`
+    shard_key: bool,
+    unique: bool,
+    /// `Some(None)` for a default index, `Some(Some(method))` for `USING method`
+    index: Option<Option<String>>,
+    default: Option<String>,
+    check: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum JsonKind {
+    Json,
+    Jsonb,
+}
+
+impl JsonKind {
+    fn ddl_type(self) -> &'static str {
+        match self {
+            JsonKind::Json => "JSON",
+            JsonKind::Jsonb => "JSONB",
+        }
+    }
+
+    fn pg_type(self) -> TokenStream2 {
+        match self {
+            JsonKind::Json => quote! { ::batch_copy::__private::Type::JSON },
+            JsonKind::Jsonb => quote! { ::batch_copy::__private::Type::JSONB },
+        }
+    }
+}
+
+fn parse_field_attrs(field: &syn::Field) -> syn::Result<FieldAttrs> {
+    let mut out = FieldAttrs::default();
+    for attr in &field.attrs {
+        if attr.path().is_ident("batch_copy") {
+            let nested =
+                attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+            for meta in nested {
+                match &meta {
+                    Meta::Path(p) if p.is_ident("json") => out.json = Some(JsonKind::Json),
+                    Meta::Path(p) if p.is_ident("jsonb") => out.json = Some(JsonKind::Jsonb),
+                    Meta::Path(p) if p.is_ident("flatten") => out.flatten = true,
+                    Meta::Path(p) if p.is_ident("primary_key") => out.primary_key = true,
+                    Meta::Path(p) if p.is_ident("conflict_key") => out.conflict_key = true,
+                    Meta::Path(p) if p.is_ident("no_update") => out.no_update = true,
+                    Meta::Path(p) if p.is_ident("replace_key") => out.replace_key = true,
+                    Meta::Path(p) if p.is_ident("shard_key") => out.shard_key = true,
+                    Meta::Path(p) if p.is_ident("unique") => out.unique = true,
+                    Meta::Path(p) if p.is_ident("index") => out.index = Some(None),
+                    Meta::NameValue(nv) if nv.path.is_ident("index") => {
+                        out.index = Some(Some(lit_str(&nv.value)?))
+                    }
+                    Meta::NameValue(nv) if nv.path.is_ident("default") => {
+                        out.default = Some(lit_str(&nv.value)?)
+                    }
+                    Meta::NameValue(nv) if nv.path.is_ident("check") => {
+                        out.check = Some(lit_str(&nv.value)?)
+                    }
+                    Meta::List(l) if l.path.is_ident("numeric") => {
+                        let args = l.parse_args_with(
+                            Punctuated::<syn::LitInt, Token![,]>::parse_terminated,
+                        )?;
+                        let mut args = args.iter();
+                        let precision = match args.next() {
+                            Some(p) => p.base10_parse()?,
+                            None => {
+                                return Err(syn::Error::new_spanned(
+                                    l,
+                                    "numeric(...) requires a precision, e.g. numeric(18, 6)",
+                                ))
+                            }
+                        };
+                        let scale = args.next().map(|s| s.base10_parse()).transpose()?;
+                        out.numeric = Some((precision, scale));
+                    }
+                    _ => {
+                        return Err(syn::Error::new_spanned(
+                            meta,
+                            "unknown batch_copy field attribute",
+                        ))
+                    }
+                }
+            }
+        }
+    }
+
+    if out.json.is_some() || out.numeric.is_some() {
+        if let Some(attr) = field.attrs.iter().find(|a| a.path().is_ident("pg")) {
+            return Err(syn::Error::new_spanned(
+                attr,
+                "#[pg(...)] cannot be combined with #[batch_copy(json)], \
+                 #[batch_copy(jsonb)] or #[batch_copy(numeric(...))]",
+            ));
+        }
+    }
+    if out.flatten
+        && (out.json.is_some()
+            || out.numeric.is_some()
+            || field.attrs.iter().any(|a| a.path().is_ident("pg")))
+    {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "a flattened field takes its columns from the nested struct \
+             and cannot have other type attributes",
+        ));
+    }
+    if out.flatten
+        && (out.primary_key
+            || out.conflict_key
+            || out.no_update
+            || out.replace_key
+            || out.shard_key
+            || out.unique
+            || out.index.is_some()
+            || out.default.is_some()
+            || out.check.is_some())
+    {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "constraints of a flattened field belong on the nested struct's fields",
+        ));
+    }
+    if out.primary_key && is_option(&field.ty) {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "primary key columns cannot be optional",
+        ));
+    }
+    if out.replace_key && is_option(&field.ty) {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "replace key columns cannot be optional",
+        ));
+    }
+    if out.flatten && is_option(&field.ty) {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "flattened fields cannot be optional",
+        ));
+    }
+    if out.json.is_some() && out.numeric.is_some() {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "a field cannot be both json and numeric",
+        ));
+    }
+    if out.numeric.is_some()
+        && !matches!(
+            base_type_name(&field.ty).as_deref(),
+            Some("Decimal" | "BigDecimal")
+        )
+    {
+        return Err(syn::Error::new_spanned(
+            &field.ty,
+            "#[batch_copy(numeric(...))] requires a rust_decimal::Decimal or \
+             bigdecimal::BigDecimal field",
+        ));
+    }
+    Ok(out)
+}
+
+fn lit_str(expr: &Expr) -> syn::Result<String> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) => Ok(s.value()),
+        _ => Err(syn::Error::new_spanned(expr, "expected a string literal")),
+    }
+}
+
+/// The field's doc comment as one line, for `COMMENT ON COLUMN`
+fn doc_comment(field: &syn::Field) -> Option<String> {
+    let lines: Vec<String> = field
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("doc"))
+        .filter_map(|a| match &a.meta {
+            Meta::NameValue(nv) => lit_str(&nv.value).ok(),
+            _ => None,
+        })
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+/// The last path segment of a type, looking through `Option<T>`
+fn base_type_name(ty: &Type) -> Option<String> {
+    if let Type::Path(tp) = ty {
+        let last = tp.path.segments.last()?;
+        if last.ident == "Option" {
+            if let PathArguments::AngleBracketed(ab) = &last.arguments {
+                if let Some(GenericArgument::Type(inner)) = ab.args.first() {
+                    return base_type_name(inner);
+                }
+            }
+            return None;
+        }
+        return Some(last.ident.to_string());
+    }
+    None
+}
+
+/// Push a reference to the field onto `out` in `fill_copy_refs`, going
+/// through a `ToSql` wrapper for types that need one.
+fn field_push(field: &syn::Field, attrs: &FieldAttrs) -> TokenStream2 {
+    let id = field.ident.as_ref().unwrap();
+    let optional = is_option(&field.ty);
+    if attrs.json.is_some() {
+        return if optional {
+            quote! { out.push(::batch_copy::__private::json_option(&self.#id)); }
+        } else {
+            quote! {
+                out.push(::batch_copy::__private::Json::from_ref(&self.#id) as &(dyn ::batch_copy::__private::ToSql + Sync));
+            }
+        };
+    }
+    if base_type_name(&field.ty).as_deref() == Some("BigDecimal") {
+        return if optional {
+            quote! { out.push(::batch_copy::__private::numeric_option(&self.#id)); }
+        } else {
+            quote! {
+                out.push(::batch_copy::__private::Numeric::from_ref(&self.#id) as &(dyn ::batch_copy::__private::ToSql + Sync));
+            }
+        };
+    }
+    quote! { out.push(&self.#id as &(dyn ::batch_copy::__private::ToSql + Sync)); }
+}
+
+fn field_pg_type(field: &syn::Field, attrs: &FieldAttrs) -> syn::Result<TokenStream2> {
+    if let Some(kind) = attrs.json {
+        return Ok(kind.pg_type());
+    }
+    if attrs.numeric.is_some() {
+        return Ok(quote! { ::batch_copy::__private::Type::NUMERIC });
+    }
     for attr in &field.attrs {
         if attr.path().is_ident("pg") {
             let variant = attr.parse_args::<Ident>()?;
@@ -159,6 +950,10 @@ fn field_pg_type(field: &syn::Field) -> syn::Result<TokenStream2> {
     })
 }
 
+fn is_u8(ty: &Type) -> bool {
+    matches!(ty, Type::Path(tp) if tp.path.is_ident("u8"))
+}
+
 fn is_option(ty: &Type) -> bool {
     if let Type::Path(tp) = ty {
         if let Some(last) = tp.path.segments.last() {
@@ -168,12 +963,22 @@ fn is_option(ty: &Type) -> bool {
     false
 }
 
-fn field_ddl_info(field: &syn::Field) -> syn::Result<(String, bool)> {
+fn field_ddl_info(field: &syn::Field, attrs: &FieldAttrs) -> syn::Result<(String, bool)> {
     let nullable = is_option(&field.ty);
+    if let Some(kind) = attrs.json {
+        return Ok((kind.ddl_type().to_string(), nullable));
+    }
+    match attrs.numeric {
+        Some((precision, Some(scale))) => {
+            return Ok((format!("NUMERIC({precision},{scale})"), nullable))
+        }
+        Some((precision, None)) => return Ok((format!("NUMERIC({precision})"), nullable)),
+        None => {}
+    }
     for attr in &field.attrs {
         if attr.path().is_ident("pg") {
             let variant = attr.parse_args::<Ident>()?;
-            return Ok((variant.to_string(), nullable));
+            return Ok((pg_ident_to_ddl(&variant.to_string()), nullable));
         }
     }
     infer_ddl_type(&field.ty)
@@ -187,6 +992,42 @@ fn field_ddl_info(field: &syn::Field) -> syn::Result<(String, bool)> {
         })
 }
 
+/// Spell a `Type` constant name the way DDL does, e.g. `TSTZ_RANGE` is `TSTZRANGE`
+/// and `INT8_ARRAY` is `INT8[]`
+fn pg_ident_to_ddl(name: &str) -> String {
+    match name.strip_suffix("_ARRAY") {
+        Some(element) => format!("{}[]", pg_ident_to_ddl(element)),
+        None => name.replace('_', ""),
+    }
+}
+
+/// The last path segment name of the first type argument, e.g. `i64` in `PgRange<i64>`
+fn first_type_arg_name(args: &PathArguments) -> Option<String> {
+    if let PathArguments::AngleBracketed(ab) = args {
+        if let Some(GenericArgument::Type(Type::Path(inner))) = ab.args.first() {
+            return inner.path.segments.last().map(|s| s.ident.to_string());
+        }
+    }
+    None
+}
+
+/// Whether a type with a generic name such as `Value` or `Point` is written with
+/// the path of the crate it is mapped for, e.g. `serde_json::Value`, so that a
+/// user type of the same name is not mistaken for it
+fn has_expected_crate(path: &syn::Path, name: &str) -> bool {
+    let crates: &[&str] = match name {
+        "Value" => &["serde_json"],
+        "Point" | "Rect" | "LineString" => &["geo_types", "geo"],
+        "MacAddress" => &["eui48"],
+        _ => return true,
+    };
+    let segments = &path.segments;
+    segments.len() >= 2
+        && crates
+            .iter()
+            .any(|c| segments[segments.len() - 2].ident == c)
+}
+
 fn infer_ddl_type(ty: &Type) -> Option<String> {
     match ty {
         Type::Path(tp) => {
@@ -213,6 +1054,23 @@ fn infer_ddl_type(ty: &Type) -> Option<String> {
                 return None;
             }
 
+            if name == "PgRange" {
+                let range = match first_type_arg_name(&last.arguments)?.as_str() {
+                    "i32" => "INT4RANGE",
+                    "i64" => "INT8RANGE",
+                    "Decimal" => "NUMRANGE",
+                    "NaiveDate" => "DATERANGE",
+                    "NaiveDateTime" => "TSRANGE",
+                    "DateTime" => "TSTZRANGE",
+                    _ => return None,
+                };
+                return Some(range.to_string());
+            }
+
+            if !has_expected_crate(&tp.path, &name) {
+                return None;
+            }
+
             Some(
                 match name.as_str() {
                     "bool" => "BOOLEAN",
@@ -227,19 +1085,23 @@ fn infer_ddl_type(ty: &Type) -> Option<String> {
                     "NaiveDateTime" => "TIMESTAMP",
                     "DateTime" => "TIMESTAMPTZ",
                     "Uuid" => "UUID",
+                    "Decimal" | "BigDecimal" => "NUMERIC",
+                    "IpAddr" => "INET",
+                    "MacAddress" => "MACADDR",
+                    "Point" => "POINT",
+                    "Rect" => "BOX",
+                    "LineString" => "PATH",
+                    "Value" => "JSONB",
                     _ => return None,
                 }
                 .to_string(),
             )
         }
-        Type::Reference(tr) => {
-            if let Type::Path(inner) = tr.elem.as_ref() {
-                if inner.path.is_ident("str") {
-                    return Some("TEXT".to_string());
-                }
-            }
-            None
-        }
+        Type::Reference(tr) => match tr.elem.as_ref() {
+            Type::Path(inner) if inner.path.is_ident("str") => Some("TEXT".to_string()),
+            Type::Slice(inner) if is_u8(&inner.elem) => Some("BYTEA".to_string()),
+            _ => None,
+        },
         _ => None,
     }
 }
@@ -272,6 +1134,22 @@ fn infer_pg_type(ty: &Type) -> Option<TokenStream2> {
                 return None;
             }
 
+            if name == "PgRange" {
+                return Some(match first_type_arg_name(&last.arguments)?.as_str() {
+                    "i32" => quote! { ::batch_copy::__private::Type::INT4_RANGE },
+                    "i64" => quote! { ::batch_copy::__private::Type::INT8_RANGE },
+                    "Decimal" => quote! { ::batch_copy::__private::Type::NUM_RANGE },
+                    "NaiveDate" => quote! { ::batch_copy::__private::Type::DATE_RANGE },
+                    "NaiveDateTime" => quote! { ::batch_copy::__private::Type::TS_RANGE },
+                    "DateTime" => quote! { ::batch_copy::__private::Type::TSTZ_RANGE },
+                    _ => return None,
+                });
+            }
+
+            if !has_expected_crate(&tp.path, &name) {
+                return None;
+            }
+
             Some(match name.as_str() {
                 "bool" => quote! { ::batch_copy::__private::Type::BOOL },
                 "i8" | "i16" => quote! { ::batch_copy::__private::Type::INT2 },
@@ -285,17 +1163,25 @@ fn infer_pg_type(ty: &Type) -> Option<TokenStream2> {
                 "NaiveDateTime" => quote! { ::batch_copy::__private::Type::TIMESTAMP },
                 "DateTime" => quote! { ::batch_copy::__private::Type::TIMESTAMPTZ },
                 "Uuid" => quote! { ::batch_copy::__private::Type::UUID },
+                "Decimal" | "BigDecimal" => quote! { ::batch_copy::__private::Type::NUMERIC },
+                "IpAddr" => quote! { ::batch_copy::__private::Type::INET },
+                "MacAddress" => quote! { ::batch_copy::__private::Type::MACADDR },
+                "Point" => quote! { ::batch_copy::__private::Type::POINT },
+                "Rect" => quote! { ::batch_copy::__private::Type::BOX },
+                "LineString" => quote! { ::batch_copy::__private::Type::PATH },
+                "Value" => quote! { ::batch_copy::__private::Type::JSONB },
                 _ => return None,
             })
         }
-        Type::Reference(tr) => {
-            if let Type::Path(inner) = tr.elem.as_ref() {
-                if inner.path.is_ident("str") {
-                    return Some(quote! { ::batch_copy::__private::Type::TEXT });
-                }
+        Type::Reference(tr) => match tr.elem.as_ref() {
+            Type::Path(inner) if inner.path.is_ident("str") => {
+                Some(quote! { ::batch_copy::__private::Type::TEXT })
             }
-            None
-        }
+            Type::Slice(inner) if is_u8(&inner.elem) => {
+                Some(quote! { ::batch_copy::__private::Type::BYTEA })
+            }
+            _ => None,
+        },
         _ => None,
     }
 }
@@ -1,11 +1,13 @@
 #![allow(clippy::clone_on_copy)]
 use futures::stream::StreamExt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::Result;
 use chrono::prelude::*;
 use csv_async::AsyncReaderBuilder;
 use glob::glob;
+use rust_decimal::Decimal;
 use tokio::fs::File;
 use tokio::task::JoinSet;
 use batch_copy::{BatchCopy, Configuration, Copier};
@@ -18,7 +20,8 @@ struct SpotPrice {
     os: String,
     region: String,
     az: String,
-    price: f64,
+    #[batch_copy(numeric(12, 6))]
+    price: Decimal,
 }
 
 // An async producer function which uses the copier to send rows
@@ -33,7 +36,7 @@ async fn copy_csv(path: PathBuf, copier: Copier<SpotPrice>) -> (PathBuf, usize,
     let mut skipped = 0;
 
     // Parse the CSV record into a SpotPrice
-    type Record = (String, String, String, String, f64);
+    type Record = (String, String, String, String, String);
     let mut results = rdr.deserialize::<Record>();
 
     // Loop through rows and send to copier
@@ -50,6 +53,16 @@ async fn copy_csv(path: PathBuf, copier: Copier<SpotPrice>) -> (PathBuf, usize,
                 }
             };
 
+            // Parse the price as an exact decimal
+            let price = match Decimal::from_str(&price) {
+                Ok(p) => p,
+                Err(_) => {
+                    eprintln!("error, cannot parse price, skipping");
+                    skipped += 1;
+                    continue;
+                }
+            };
+
             // split region and az (the last char)
             let len = region_az.len();
             let region = (region_az[..len - 1]).to_owned();
@@ -87,6 +100,7 @@ async fn main() -> Result<()> {
         .flush_timer_ms(2000)
         .max_rows_per_batch(80000)
         .max_channel_capacity(80000)
+        .create_table_if_missing(true)
         .build();
     let copier = Copier::<SpotPrice>::new(copy_cfg).await?;
 
@@ -1,97 +1,150 @@
-use futures_util::pin_mut;
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt::Debug;
 use std::mem;
+use std::sync::Arc;
 
-use bb8_postgres::PostgresConnectionManager;
 use tokio::sync::{mpsc, oneshot};
-use tokio::time::{interval, Duration};
-use tokio_postgres::binary_copy::BinaryCopyInWriter;
-use tokio_postgres::types::ToSql;
-use tokio_postgres::NoTls;
+use tokio::time::{interval_at, Duration, Instant};
 
+use crate::errors::BatchCopyDatabaseError;
+use crate::sequence::Sequences;
+use crate::sink::{Batch, BatchSink};
+use crate::stats::Counters;
 use crate::BatchCopyRow;
 
-type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
-
-pub struct BatchCopyActor<T: BatchCopyRow + Send> {
+pub struct BatchCopyActor<T: BatchCopyRow + Send, S> {
     recv: mpsc::Receiver<BatchCopyMessage<T>>,
     rows: Vec<T>,
+    /// rows already encoded as binary COPY tuples by the sender
+    encoded: BytesMut,
+    /// tuples encoded for tables other than `T::TABLE`, and their count
+    tables: HashMap<String, (BytesMut, usize)>,
+    encoded_rows: usize,
     rows_per_batch: usize,
-    pool: Pool,
+    sink: S,
+    sequences: Option<Sequences>,
+    stats: Arc<Counters>,
+    /// batches given up on since the last successful swap
+    failed_since_swap: u64,
 }
 
 #[derive(Debug)]
 pub(crate) enum BatchCopyMessage<T: BatchCopyRow + Send> {
     InsertRow(T, oneshot::Sender<usize>),
+    /// encoded tuples, their count, and their table if not `T::TABLE`; answers 0
+    /// if they filled a batch that failed
+    InsertEncoded(Bytes, usize, Option<String>, oneshot::Sender<usize>),
+    /// a row with its producer id and sequence number
+    InsertSequenced(T, String, i64, oneshot::Sender<usize>),
+    /// answers 0 if the buffered rows failed
     Flush(oneshot::Sender<usize>),
+    Swap(oneshot::Sender<Result<(), BatchCopyDatabaseError>>),
 }
 
-impl<T> BatchCopyActor<T>
+impl<T, S> BatchCopyActor<T, S>
 where
     T: BatchCopyRow + Send,
+    S: BatchSink<T>,
 {
     pub(crate) fn new(
         recv: mpsc::Receiver<BatchCopyMessage<T>>,
-        pool: Pool,
+        sink: S,
         rows_per_batch: usize,
+        sequences: Option<Sequences>,
+        stats: Arc<Counters>,
     ) -> Self {
         let rows = vec![];
         Self {
             recv,
             rows,
+            encoded: BytesMut::new(),
+            tables: HashMap::new(),
+            encoded_rows: 0,
             rows_per_batch,
-            pool,
+            sink,
+            sequences,
+            stats,
+            failed_since_swap: 0,
         }
     }
 
-    async fn flush(&mut self) -> u64 {
+    fn buffered(&self) -> usize {
+        self.rows.len() + self.encoded_rows
+    }
+
+    /// Write out the buffered rows, returning whether they all reached the sink
+    async fn flush(&mut self) -> bool {
         // Exit early if there's nothing to flush
-        if self.rows.is_empty() {
-            return 0;
+        if self.buffered() == 0 {
+            return true;
         }
 
-        // Start connection
-        let mut connection = self.pool.get().await.unwrap();
-        let transaction = connection.transaction().await.unwrap();
-
         // Swap out rows
         let mut target_rows: Vec<T> = Vec::with_capacity(self.rows.len());
         mem::swap(&mut self.rows, &mut target_rows);
-        let nrows = &target_rows.len();
-
-        // see https://github.com/sfackler/rust-postgres/blob/master/tokio-postgres/tests/test/binary_copy.rs
-        let sink_result = transaction.copy_in(T::COPY_STATEMENT).await;
-        if let Err(e) = sink_result {
-            log::error!("\tterminating transaction, COPY is invalid\n\t{e}");
-            return 0;
-        }
-        let sink = sink_result.unwrap();
-
-        let writer = BinaryCopyInWriter::new(sink, T::TYPES);
-        pin_mut!(writer);
-
-        let mut errors = false;
-        let mut row_refs: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(T::TYPES.len());
-        for row in target_rows.iter() {
-            row_refs.clear();
-            row.fill_copy_refs(&mut row_refs);
-            if let Err(e) = writer.as_mut().write(row_refs.as_slice()).await {
-                log::error!("Error in COPY:\n\t{e}");
-                errors = true;
-                break;
+        let mut batch = Batch {
+            len: target_rows.len() + mem::take(&mut self.encoded_rows),
+            rows: target_rows,
+            encoded: self.encoded.split().freeze(),
+            tables: self
+                .tables
+                .drain()
+                .map(|(table, (buf, n))| (table, (buf.freeze(), n)))
+                .collect(),
+            routed: vec![],
+            marks: self
+                .sequences
+                .as_mut()
+                .map(Sequences::take_pending)
+                .unwrap_or_default(),
+        };
+
+        let mut attempt = 0;
+        loop {
+            match self.write_batch(&mut batch).await {
+                Ok(nrows) => {
+                    if let Some(sequences) = &mut self.sequences {
+                        sequences.committed(batch.marks);
+                    }
+                    self.stats.committed(nrows);
+                    return true;
+                }
+                Err(e) if self.sink.retry(&*e, attempt).await => attempt += 1,
+                Err(e) => {
+                    log::error!("Error in COPY:\n\t{e}");
+                    log::error!(
+                        "\tterminating transaction, data loss has occured! {} rows discarded",
+                        batch.len
+                    );
+                    self.stats.failed(batch.len as u64);
+                    self.failed_since_swap += 1;
+                    return false;
+                }
             }
         }
+    }
 
-        if !errors {
-            let nrows = writer.finish().await.unwrap();
-            transaction.commit().await.unwrap();
-            nrows
-        } else {
-            log::error!("\tterminating transaction, data loss has occured! {nrows} rows discarded");
-            // explicit rollback seems to hang, instead we rely on the transaction Drop
-            // transaction.rollback().await.unwrap();
-
-            0
+    /// Write and commit a batch through the sink, returning the rows written
+    async fn write_batch(
+        &mut self,
+        batch: &mut Batch<T>,
+    ) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        let len = batch.len;
+        let transaction = self.sink.begin(batch).await;
+        self.stats.discarded((len - batch.len) as u64);
+        let mut transaction = transaction?;
+
+        match self.sink.write(&mut transaction, batch).await {
+            Ok(nrows) => {
+                self.sink.commit(transaction).await?;
+                Ok(nrows)
+            }
+            Err(e) => {
+                self.sink.abort(transaction).await;
+                Err(e)
+            }
         }
     }
 
@@ -99,25 +152,72 @@ where
         match msg {
             BatchCopyMessage::InsertRow(row, output_chan) => {
                 self.rows.push(row);
-                if self.rows.len() >= self.rows_per_batch {
+                if self.buffered() >= self.rows_per_batch {
                     self.flush().await;
                     // set the last_flushed
                 }
                 output_chan.send(1).unwrap();
             }
-            BatchCopyMessage::Flush(output_chan) => {
-                self.flush().await;
+            BatchCopyMessage::InsertSequenced(row, producer, seq, output_chan) => {
+                // only sent by a copier with a sequence table
+                let accepted = self
+                    .sequences
+                    .as_mut()
+                    .is_none_or(|sequences| sequences.accept(&producer, seq));
+                if !accepted {
+                    output_chan.send(0).unwrap();
+                    return;
+                }
+                self.rows.push(row);
+                if self.buffered() >= self.rows_per_batch {
+                    self.flush().await;
+                }
                 output_chan.send(1).unwrap();
             }
+            BatchCopyMessage::InsertEncoded(tuples, n, table, output_chan) => {
+                match table {
+                    Some(table) => {
+                        let (buf, count) = self.tables.entry(table).or_default();
+                        buf.extend_from_slice(&tuples);
+                        *count += n;
+                    }
+                    None => self.encoded.extend_from_slice(&tuples),
+                }
+                self.encoded_rows += n;
+                let copied = self.buffered() < self.rows_per_batch || self.flush().await;
+                output_chan.send(copied as usize).unwrap();
+            }
+            BatchCopyMessage::Flush(output_chan) => {
+                let copied = self.flush().await;
+                self.sink.flushed();
+                output_chan.send(copied as usize).unwrap();
+            }
+            BatchCopyMessage::Swap(output_chan) => {
+                // a snapshot missing a failed batch is never published
+                let result = if !self.flush().await || self.failed_since_swap > 0 {
+                    Err(BatchCopyDatabaseError::IncompleteSnapshot(
+                        self.failed_since_swap,
+                    ))
+                } else {
+                    self.sink.swap().await
+                };
+                if result.is_ok() {
+                    self.failed_since_swap = 0;
+                }
+                output_chan.send(result).unwrap();
+            }
         }
     }
 }
 
-pub async fn run_batch_insert_actor<T>(mut actor: BatchCopyActor<T>, timeout: u64)
+pub async fn run_batch_insert_actor<T, S>(mut actor: BatchCopyActor<T, S>, timeout: u64)
 where
     T: BatchCopyRow + Send,
+    S: BatchSink<T>,
 {
-    let mut timer = interval(Duration::from_millis(timeout));
+    // the first tick is one period out, so a batch is not flushed the moment the actor starts
+    let period = Duration::from_millis(timeout);
+    let mut timer = interval_at(Instant::now() + period, period);
     loop {
         tokio::select! {
              msg = actor.recv.recv() => match msg {
@@ -0,0 +1,590 @@
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::*;
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_schema::{DataType, SchemaRef, TimeUnit, DECIMAL128_MAX_PRECISION};
+use bytes::{BufMut, BytesMut};
+use tokio_postgres::types::{Kind, Type};
+
+use crate::binary::put_numeric;
+use crate::errors::{BatchCopyDatabaseError, ColumnMismatch};
+use crate::handler::{Configuration, Copier};
+use crate::partition::PG_EPOCH_DAYS;
+use crate::schema::create_table_ddl;
+use crate::stats::CopyStats;
+use crate::table::{
+    plain_settings, quote_columns, quote_ident, quote_table, spawn_encoded, Encoded,
+};
+
+/// Microseconds from the Unix epoch to the Postgres epoch, 2000-01-01
+const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+/// A copier for Arrow `RecordBatch`es, encoding their columns straight into
+/// binary COPY tuples without a row struct.
+///
+/// Each field of the schema is copied to the table column of the same name.
+/// Batching, flushing and failover work as for `Copier`.
+#[derive(Clone)]
+pub struct ArrowCopier {
+    copier: Copier<Encoded>,
+    schema: SchemaRef,
+    encoders: Arc<[Encoder]>,
+    /// the type each column is converted to before it is encoded, see `with_casts`
+    casts: Arc<[Option<DataType>]>,
+    /// rows sent to the actor per message
+    chunk_rows: usize,
+}
+
+impl ArrowCopier {
+    /// Check every field of `schema` against the column of the same name in
+    /// `table`, creating the table first with `create_table_if_missing`.
+    ///
+    /// Only binary `WriteMode::Copy` is supported, without partitions, sequences
+    /// or added columns.
+    pub async fn new(
+        cfg: Configuration,
+        table: &str,
+        schema: SchemaRef,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        Self::build(cfg, table, schema, false).await
+    }
+
+    /// Like `new`, but a field its column cannot take as is, such as strings for
+    /// a TIMESTAMP column, is converted with `arrow-cast` to the `arrow_type` of
+    /// the column first. A value that does not convert rejects its batch.
+    pub async fn with_casts(
+        cfg: Configuration,
+        table: &str,
+        schema: SchemaRef,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        Self::build(cfg, table, schema, true).await
+    }
+
+    async fn build(
+        cfg: Configuration,
+        table: &str,
+        schema: SchemaRef,
+        cast: bool,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        let columns = quote_columns(schema.fields().iter().map(|f| f.name().as_str()));
+        let table = &quote_table(table);
+        let ddl = match ddl_statement(table, &schema) {
+            Ok(ddl) => ddl,
+            Err(e) if cfg.create_table_if_missing => return Err(e),
+            // the DDL is only a hint for an existing table, whose column may take a cast
+            Err(e) => e.to_string(),
+        };
+
+        let pool_settings = plain_settings(&cfg)?;
+        let pool = pool_settings.build().await?;
+        let (encoders, casts) = match pool.get().await {
+            Ok(conn) => {
+                if cfg.create_table_if_missing {
+                    create_table_ddl(&*conn, table, &ddl).await?;
+                }
+                let check = format!("SELECT {columns} FROM {table} LIMIT 0");
+                let stmt = conn.prepare(&check).await.map_err(|e| {
+                    BatchCopyDatabaseError::SchemaCheckFailed {
+                        source: e,
+                        ddl: ddl.clone(),
+                    }
+                })?;
+
+                let mut encoders = Vec::with_capacity(stmt.columns().len());
+                let mut casts = Vec::with_capacity(stmt.columns().len());
+                let mut mismatches = Vec::new();
+                for (field, column) in schema.fields().iter().zip(stmt.columns()) {
+                    let ty = column.type_();
+                    let encoder = match Encoder::new(field.data_type(), ty) {
+                        Some(encoder) => Some((encoder, None)),
+                        None if cast => arrow_type(ty, column.type_modifier())
+                            .filter(|target| arrow_cast::can_cast_types(field.data_type(), target))
+                            .and_then(|target| Some((Encoder::new(&target, ty)?, Some(target)))),
+                        None => None,
+                    };
+                    match encoder {
+                        Some((encoder, target)) => {
+                            encoders.push(encoder);
+                            casts.push(target);
+                        }
+                        None => mismatches.push(ColumnMismatch::Type {
+                            column: field.name().clone(),
+                            table_type: ty.to_string(),
+                            row_type: field.data_type().to_string(),
+                        }),
+                    }
+                }
+                if !mismatches.is_empty() {
+                    return Err(BatchCopyDatabaseError::SchemaMismatch { mismatches, ddl });
+                }
+                (encoders, casts)
+            }
+            Err(_) => return Err(BatchCopyDatabaseError::BadConnection),
+        };
+
+        Ok(Self {
+            copier: spawn_encoded(
+                &cfg,
+                pool,
+                pool_settings,
+                format!("COPY {table} ({columns}) FROM STDIN (FORMAT binary)"),
+            ),
+            schema,
+            encoders: encoders.into(),
+            casts: casts.into(),
+            chunk_rows: cfg.max_rows_per_batch.max(1),
+        })
+    }
+
+    /// The schema every batch must have
+    pub fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    /// Encode the rows of `batch` on the caller's task and send them to the actor.
+    ///
+    /// A batch with another schema or a value its column cannot take, such as an
+    /// integer too large for an INT2 column, is rejected as a whole.
+    pub async fn send(&self, batch: &RecordBatch) -> Result<(), BatchCopyDatabaseError> {
+        self.send_checked(batch).await.map(|_| ())
+    }
+
+    /// `send`, returning how many of the copier's batches its rows filled and
+    /// that then failed
+    pub(crate) async fn send_checked(
+        &self,
+        batch: &RecordBatch,
+    ) -> Result<u64, BatchCopyDatabaseError> {
+        let fields = self.schema.fields();
+        let same_schema = batch.num_columns() == fields.len()
+            && batch
+                .schema_ref()
+                .fields()
+                .iter()
+                .zip(fields)
+                .all(|(a, b)| a.name() == b.name() && a.data_type() == b.data_type());
+        if !same_schema {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "the RecordBatch does not have the ArrowCopier's schema",
+            ));
+        }
+
+        let columns = batch
+            .columns()
+            .iter()
+            .zip(&*self.casts)
+            .zip(fields)
+            .map(|((array, target), field)| match target {
+                Some(target) => cast_column(array, target, field.name()),
+                None => Ok(array.clone()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut chunks = vec![];
+        let mut buf = BytesMut::new();
+        let mut rows = 0;
+        for row in 0..batch.num_rows() {
+            buf.put_i16(fields.len() as i16);
+            for ((array, encoder), field) in columns.iter().zip(&*self.encoders).zip(fields) {
+                if array.is_null(row) {
+                    buf.put_i32(-1);
+                    continue;
+                }
+                let start = buf.len();
+                buf.put_i32(0);
+                encoder.encode(array, row, &mut buf).map_err(|reason| {
+                    BatchCopyDatabaseError::InvalidValue {
+                        column: field.name().clone(),
+                        row,
+                        reason,
+                    }
+                })?;
+                let len = (buf.len() - start - 4) as i32;
+                buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+            }
+            rows += 1;
+            if rows == self.chunk_rows {
+                chunks.push((buf.split().freeze(), rows));
+                rows = 0;
+            }
+        }
+        if rows > 0 {
+            chunks.push((buf.freeze(), rows));
+        }
+
+        let mut failed = 0;
+        for (tuples, rows) in chunks {
+            if !self.copier.send_tuples(tuples, rows, None).await {
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    }
+
+    pub async fn flush(&self) {
+        self.copier.flush().await;
+    }
+
+    /// Flush, returning false if the buffered rows failed to copy
+    pub(crate) async fn flushed(&self) -> bool {
+        self.copier.flushed().await
+    }
+
+    /// Rows and batches the actor has copied or lost so far
+    pub fn stats(&self) -> CopyStats {
+        self.copier.stats()
+    }
+}
+
+/// The Postgres type a column of this Arrow type is created with
+pub fn pg_type(data_type: &DataType) -> Option<Type> {
+    Some(match data_type {
+        DataType::Boolean => Type::BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => Type::INT2,
+        DataType::Int32 | DataType::UInt16 => Type::INT4,
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => Type::INT8,
+        DataType::Float32 => Type::FLOAT4,
+        DataType::Float64 => Type::FLOAT8,
+        DataType::Decimal128(..) => Type::NUMERIC,
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => Type::TEXT,
+        DataType::Binary
+        | DataType::LargeBinary
+        | DataType::BinaryView
+        | DataType::FixedSizeBinary(_) => Type::BYTEA,
+        DataType::Date32 | DataType::Date64 => Type::DATE,
+        DataType::Time32(_) | DataType::Time64(_) => Type::TIME,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
+        DataType::Duration(_) => Type::INTERVAL,
+        _ => return None,
+    })
+}
+
+/// The Arrow type `ArrowCopier::with_casts` converts a field to for a column of
+/// type `ty` and type modifier `typmod`, which holds a NUMERIC's precision and scale
+pub fn arrow_type(ty: &Type, typmod: i32) -> Option<DataType> {
+    if let Kind::Domain(base) = ty.kind() {
+        return arrow_type(base, typmod);
+    }
+    if matches!(ty.kind(), Kind::Enum(_)) {
+        return Some(DataType::Utf8);
+    }
+    Some(match *ty {
+        Type::BOOL => DataType::Boolean,
+        Type::INT2 => DataType::Int16,
+        Type::INT4 => DataType::Int32,
+        Type::INT8 => DataType::Int64,
+        Type::FLOAT4 => DataType::Float32,
+        Type::FLOAT8 => DataType::Float64,
+        Type::NUMERIC => numeric_type(typmod)?,
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::JSON | Type::JSONB => {
+            DataType::Utf8
+        }
+        Type::BYTEA => DataType::Binary,
+        Type::DATE => DataType::Date32,
+        Type::TIME => DataType::Time64(TimeUnit::Microsecond),
+        Type::TIMESTAMP => DataType::Timestamp(TimeUnit::Microsecond, None),
+        Type::TIMESTAMPTZ => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        Type::INTERVAL => DataType::Duration(TimeUnit::Microsecond),
+        _ => return None,
+    })
+}
+
+/// `Decimal128` with the precision and scale of a `NUMERIC(p, s)` type modifier.
+/// Without them any scale is allowed, and no fixed scale converts every value
+/// without rounding it.
+fn numeric_type(typmod: i32) -> Option<DataType> {
+    if typmod < 4 {
+        return None;
+    }
+    let precision = ((typmod - 4) >> 16) & 0xffff;
+    // 11 bits, negative since Postgres 15
+    let scale = (((typmod - 4) & 0x7ff) ^ 0x400) - 0x400;
+    (precision <= DECIMAL128_MAX_PRECISION as i32)
+        .then_some(DataType::Decimal128(precision as u8, scale as i8))
+}
+
+/// Convert `array` to `target`, rejecting the first value that does not convert
+pub(crate) fn cast_column(
+    array: &ArrayRef,
+    target: &DataType,
+    column: &str,
+) -> Result<ArrayRef, BatchCopyDatabaseError> {
+    let invalid = |row, reason| BatchCopyDatabaseError::InvalidValue {
+        column: column.to_string(),
+        row,
+        reason,
+    };
+    // a safe cast turns values it cannot convert into nulls, which locates them
+    let cast = arrow_cast::cast(array, target).map_err(|e| invalid(0, e.to_string()))?;
+    match (0..array.len()).find(|&row| cast.is_null(row) && !array.is_null(row)) {
+        Some(row) => Err(invalid(
+            row,
+            format!("cannot convert {} to {target}", array.data_type()),
+        )),
+        None => Ok(cast),
+    }
+}
+
+/// `CREATE TABLE` with a column of the `pg_type` of each field, failing on the
+/// first field that has none
+fn ddl_statement(
+    table: &str,
+    schema: &arrow_schema::Schema,
+) -> Result<String, BatchCopyDatabaseError> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let sql_type = match (field.data_type(), pg_type(field.data_type())) {
+                (DataType::Decimal128(precision, scale), _) if *scale >= 0 => {
+                    format!("NUMERIC({precision},{scale})")
+                }
+                (_, Some(ty)) => ty.name().to_uppercase(),
+                (data_type, None) => {
+                    return Err(BatchCopyDatabaseError::UnsupportedType {
+                        column: field.name().clone(),
+                        data_type: data_type.to_string(),
+                    })
+                }
+            };
+            let null = if field.is_nullable() { "" } else { " NOT NULL" };
+            Ok(format!("    {} {sql_type}{null}", quote_ident(field.name())))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .join(",\n");
+    Ok(format!("CREATE TABLE {table} (\n{columns}\n);"))
+}
+
+/// How the values of one Arrow column are written for its table column
+#[derive(Debug, Clone, Copy)]
+enum Encoder {
+    Bool,
+    /// any integer into INT2, INT4 or INT8 of this many bytes, if it fits
+    Int(usize),
+    /// any integer into NUMERIC
+    IntNumeric,
+    Float4,
+    /// FLOAT4 or FLOAT8 into FLOAT8
+    Float8,
+    /// a Decimal128 with this scale into NUMERIC
+    Decimal(i8),
+    /// strings into text and enum columns
+    Text,
+    Jsonb,
+    /// binary into BYTEA
+    Bytes,
+    /// 16 bytes into UUID
+    Uuid,
+    /// Date32 or Date64 into DATE
+    Date,
+    /// Time32 or Time64 of this unit into TIME
+    Time(TimeUnit),
+    /// a timestamp of this unit into TIMESTAMP or TIMESTAMPTZ
+    Timestamp(TimeUnit),
+    /// a Duration of this unit into INTERVAL
+    Interval(TimeUnit),
+}
+
+impl Encoder {
+    /// `None` if values of `data_type` cannot be written to a column of type `ty`
+    fn new(data_type: &DataType, ty: &Type) -> Option<Self> {
+        use DataType as D;
+
+        let integer = matches!(
+            data_type,
+            D::Int8 | D::Int16 | D::Int32 | D::Int64 | D::UInt8 | D::UInt16 | D::UInt32 | D::UInt64
+        );
+        let string = matches!(data_type, D::Utf8 | D::LargeUtf8 | D::Utf8View);
+        let binary = matches!(
+            data_type,
+            D::Binary | D::LargeBinary | D::BinaryView | D::FixedSizeBinary(_)
+        );
+        if let Kind::Domain(base) = ty.kind() {
+            return Self::new(data_type, base);
+        }
+        if string && matches!(ty.kind(), Kind::Enum(_)) {
+            return Some(Self::Text);
+        }
+        Some(match (data_type, ty) {
+            (D::Boolean, &Type::BOOL) => Self::Bool,
+            (_, &Type::INT2) if integer => Self::Int(2),
+            (_, &Type::INT4) if integer => Self::Int(4),
+            (_, &Type::INT8) if integer => Self::Int(8),
+            (_, &Type::NUMERIC) if integer => Self::IntNumeric,
+            (D::Float32, &Type::FLOAT4) => Self::Float4,
+            (D::Float32 | D::Float64, &Type::FLOAT8) => Self::Float8,
+            (D::Decimal128(_, scale), &Type::NUMERIC) => Self::Decimal(*scale),
+            (_, &Type::TEXT | &Type::VARCHAR | &Type::BPCHAR | &Type::NAME | &Type::JSON)
+                if string =>
+            {
+                Self::Text
+            }
+            (_, &Type::JSONB) if string => Self::Jsonb,
+            (_, &Type::BYTEA) if binary => Self::Bytes,
+            (_, &Type::UUID) if binary => Self::Uuid,
+            (D::Date32 | D::Date64, &Type::DATE) => Self::Date,
+            (D::Time32(unit) | D::Time64(unit), &Type::TIME) => Self::Time(*unit),
+            (D::Timestamp(unit, _), &Type::TIMESTAMP | &Type::TIMESTAMPTZ) => {
+                Self::Timestamp(*unit)
+            }
+            (D::Duration(unit), &Type::INTERVAL) => Self::Interval(*unit),
+            _ => return None,
+        })
+    }
+
+    /// Append the binary value at `row`, which is not null
+    fn encode(&self, array: &dyn Array, row: usize, buf: &mut BytesMut) -> Result<(), String> {
+        match self {
+            Self::Bool => buf.put_u8(array.as_boolean().value(row) as u8),
+            Self::Int(bytes) => {
+                let v = int_at(array, row);
+                let fits = match bytes {
+                    2 => i16::try_from(v).map(|v| buf.put_i16(v)),
+                    4 => i32::try_from(v).map(|v| buf.put_i32(v)),
+                    _ => i64::try_from(v).map(|v| buf.put_i64(v)),
+                };
+                fits.map_err(|_| format!("{v} does not fit in INT{bytes}"))?;
+            }
+            Self::IntNumeric => put_decimal(int_at(array, row), 0, buf)?,
+            Self::Float4 => buf.put_f32(array.as_primitive::<Float32Type>().value(row)),
+            Self::Float8 => buf.put_f64(match array.data_type() {
+                DataType::Float32 => array.as_primitive::<Float32Type>().value(row) as f64,
+                _ => array.as_primitive::<Float64Type>().value(row),
+            }),
+            Self::Decimal(scale) => put_decimal(
+                array.as_primitive::<Decimal128Type>().value(row),
+                *scale,
+                buf,
+            )?,
+            Self::Text => buf.put_slice(str_at(array, row).as_bytes()),
+            Self::Jsonb => {
+                buf.put_u8(1);
+                buf.put_slice(str_at(array, row).as_bytes());
+            }
+            Self::Bytes => buf.put_slice(bytes_at(array, row)),
+            Self::Uuid => {
+                let bytes = bytes_at(array, row);
+                if bytes.len() != 16 {
+                    return Err(format!("a UUID needs 16 bytes, not {}", bytes.len()));
+                }
+                buf.put_slice(bytes);
+            }
+            Self::Date => buf.put_i32(match array.data_type() {
+                DataType::Date64 => {
+                    let millis = array.as_primitive::<Date64Type>().value(row);
+                    let days = millis.div_euclid(MILLIS_PER_DAY) - PG_EPOCH_DAYS;
+                    i32::try_from(days).map_err(|_| format!("{millis} ms is out of range"))?
+                }
+                _ => {
+                    let days = array.as_primitive::<Date32Type>().value(row);
+                    days.checked_sub(PG_EPOCH_DAYS as i32)
+                        .ok_or_else(|| format!("{days} days is out of range"))?
+                }
+            }),
+            Self::Time(unit) => {
+                let v = match array.data_type() {
+                    DataType::Time32(TimeUnit::Second) => {
+                        array.as_primitive::<Time32SecondType>().value(row) as i64
+                    }
+                    DataType::Time32(_) => {
+                        array.as_primitive::<Time32MillisecondType>().value(row) as i64
+                    }
+                    DataType::Time64(TimeUnit::Microsecond) => {
+                        array.as_primitive::<Time64MicrosecondType>().value(row)
+                    }
+                    _ => array.as_primitive::<Time64NanosecondType>().value(row),
+                };
+                buf.put_i64(micros(v, *unit)?);
+            }
+            Self::Timestamp(unit) => {
+                let v = match unit {
+                    TimeUnit::Second => array.as_primitive::<TimestampSecondType>().value(row),
+                    TimeUnit::Millisecond => {
+                        array.as_primitive::<TimestampMillisecondType>().value(row)
+                    }
+                    TimeUnit::Microsecond => {
+                        array.as_primitive::<TimestampMicrosecondType>().value(row)
+                    }
+                    TimeUnit::Nanosecond => {
+                        array.as_primitive::<TimestampNanosecondType>().value(row)
+                    }
+                };
+                let micros = micros(v, *unit)?
+                    .checked_sub(PG_EPOCH_MICROS)
+                    .ok_or_else(|| format!("{v} is out of range"))?;
+                buf.put_i64(micros);
+            }
+            Self::Interval(unit) => {
+                let v = match unit {
+                    TimeUnit::Second => array.as_primitive::<DurationSecondType>().value(row),
+                    TimeUnit::Millisecond => {
+                        array.as_primitive::<DurationMillisecondType>().value(row)
+                    }
+                    TimeUnit::Microsecond => {
+                        array.as_primitive::<DurationMicrosecondType>().value(row)
+                    }
+                    TimeUnit::Nanosecond => {
+                        array.as_primitive::<DurationNanosecondType>().value(row)
+                    }
+                };
+                buf.put_i64(micros(v, *unit)?);
+                // days and months
+                buf.put_i32(0);
+                buf.put_i32(0);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn micros(v: i64, unit: TimeUnit) -> Result<i64, String> {
+    match unit {
+        TimeUnit::Second => v
+            .checked_mul(1_000_000)
+            .ok_or_else(|| format!("{v} s is out of range")),
+        TimeUnit::Millisecond => v
+            .checked_mul(1_000)
+            .ok_or_else(|| format!("{v} ms is out of range")),
+        TimeUnit::Microsecond => Ok(v),
+        TimeUnit::Nanosecond => Ok(v.div_euclid(1_000)),
+    }
+}
+
+fn int_at(array: &dyn Array, row: usize) -> i128 {
+    match array.data_type() {
+        DataType::Int8 => array.as_primitive::<Int8Type>().value(row) as i128,
+        DataType::Int16 => array.as_primitive::<Int16Type>().value(row) as i128,
+        DataType::Int32 => array.as_primitive::<Int32Type>().value(row) as i128,
+        DataType::Int64 => array.as_primitive::<Int64Type>().value(row) as i128,
+        DataType::UInt8 => array.as_primitive::<UInt8Type>().value(row) as i128,
+        DataType::UInt16 => array.as_primitive::<UInt16Type>().value(row) as i128,
+        DataType::UInt32 => array.as_primitive::<UInt32Type>().value(row) as i128,
+        _ => array.as_primitive::<UInt64Type>().value(row) as i128,
+    }
+}
+
+fn str_at(array: &dyn Array, row: usize) -> &str {
+    match array.data_type() {
+        DataType::LargeUtf8 => array.as_string::<i64>().value(row),
+        DataType::Utf8View => array.as_string_view().value(row),
+        _ => array.as_string::<i32>().value(row),
+    }
+}
+
+fn bytes_at(array: &dyn Array, row: usize) -> &[u8] {
+    match array.data_type() {
+        DataType::LargeBinary => array.as_binary::<i64>().value(row),
+        DataType::BinaryView => array.as_binary_view().value(row),
+        DataType::FixedSizeBinary(_) => array.as_fixed_size_binary().value(row),
+        _ => array.as_binary::<i32>().value(row),
+    }
+}
+
+/// NUMERIC of `value * 10^-scale`
+fn put_decimal(value: i128, scale: i8, buf: &mut BytesMut) -> Result<(), String> {
+    let digits = value.unsigned_abs().to_string();
+    put_numeric(value < 0, digits, scale.into(), buf).map_err(|e| e.to_string())
+}
@@ -0,0 +1,402 @@
+//! Load CSV, TSV and NDJSON files into an existing Postgres table.
+//!
+//! Column types are read from the table, and each field is parsed for its
+//! column before it is sent, so a row that does not parse is skipped and
+//! counted rather than failing its batch.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::{Parser as _, ValueEnum};
+use csv_async::{AsyncReaderBuilder, StringRecord};
+use futures_util::StreamExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_postgres::types::ToSql;
+
+use batch_copy::errors::BatchCopyDatabaseError;
+use batch_copy::{Configuration, TableCopier};
+
+mod value;
+
+use value::{unescape_text, Null, Parser, Value};
+
+/// Load CSV, TSV and NDJSON files into an existing Postgres table
+#[derive(Debug, clap::Parser)]
+#[command(name = "batch-copy", version)]
+struct Args {
+    /// Files to load
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Table to load into, which must exist
+    #[arg(short, long)]
+    table: String,
+
+    /// Postgres connection URL
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: String,
+
+    /// Input format; from each file's extension if not given, CSV for any other
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+
+    /// Field delimiter, `,` for CSV and a tab for TSV by default
+    #[arg(short, long)]
+    delimiter: Option<char>,
+
+    /// How the first line of a CSV or TSV file is read
+    #[arg(long, value_enum, default_value_t = Header::Names)]
+    header: Header,
+
+    /// Table columns to load, comma separated; every column by default.
+    /// Without header names, fields are taken in this order.
+    #[arg(short, long, value_delimiter = ',')]
+    columns: Vec<String>,
+
+    /// A field value read as NULL, may be repeated; an empty field for CSV and
+    /// `\N` for TSV by default
+    #[arg(long = "null", value_name = "STRING")]
+    nulls: Vec<String>,
+
+    /// Rows per COPY batch
+    #[arg(short, long, default_value_t = 8000)]
+    batch_size: usize,
+
+    /// Files loaded at once
+    #[arg(short = 'j', long, default_value_t = 4)]
+    parallel: usize,
+
+    /// Flush a partial batch after this many milliseconds
+    #[arg(long, default_value_t = 500)]
+    flush_ms: u64,
+
+    /// Skipped rows reported per file
+    #[arg(long, default_value_t = 10)]
+    report: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Csv,
+    Tsv,
+    /// one JSON object per line, its keys naming the columns
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Header {
+    /// the first line names the column of each field
+    Names,
+    /// the first line is ignored
+    Skip,
+    /// every line is data
+    None,
+}
+
+/// What loading one file came to
+struct Summary {
+    path: PathBuf,
+    sent: u64,
+    skipped: u64,
+    /// the file could not be read to the end
+    error: Option<String>,
+}
+
+/// The loader state shared by every file
+struct Loader {
+    args: Args,
+    copier: TableCopier,
+    parsers: Vec<Parser>,
+    /// column index by name
+    index: HashMap<String, usize>,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    match run(args).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Load every file, returning whether all of them were read to the end
+async fn run(args: Args) -> Result<bool, BatchCopyDatabaseError> {
+    let copy_cfg = Configuration::new()
+        .database_url(args.database_url.clone())
+        .max_rows_per_batch(args.batch_size.max(1))
+        .max_channel_capacity(args.batch_size.max(1))
+        .flush_timer_ms(args.flush_ms)
+        .build();
+    let columns: Vec<&str> = args.columns.iter().map(String::as_str).collect();
+    let columns = (!columns.is_empty()).then_some(&columns[..]);
+    let copier = TableCopier::new(copy_cfg, &args.table, columns).await?;
+
+    let mut parsers = Vec::with_capacity(copier.columns().len());
+    let mut unsupported = vec![];
+    for (name, ty) in copier.columns() {
+        match Parser::new(ty) {
+            Some(parser) => parsers.push(parser),
+            None => unsupported.push(format!("{name} ({ty})")),
+        }
+    }
+    if !unsupported.is_empty() {
+        eprintln!(
+            "error: cannot load columns of these types, leave them out with --columns: {}",
+            unsupported.join(", ")
+        );
+        return Ok(false);
+    }
+    let index = copier
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| (name.clone(), i))
+        .collect();
+
+    let parallel = args.parallel.max(1);
+    let files = args.files.clone();
+    let loader = Arc::new(Loader {
+        args,
+        copier: copier.clone(),
+        parsers,
+        index,
+    });
+    // spawned as they are pulled, so at most `parallel` files are read at once
+    let mut summaries = futures_util::stream::iter(files)
+        .map(|path| {
+            let loader = loader.clone();
+            tokio::spawn(async move { loader.load(path).await })
+        })
+        .buffered(parallel);
+
+    let (mut sent, mut skipped, mut complete) = (0, 0, true);
+    while let Some(summary) = summaries.next().await {
+        let summary = summary.expect("a file load panicked");
+        eprintln!(
+            "{}: {} rows sent, {} skipped",
+            summary.path.display(),
+            summary.sent,
+            summary.skipped
+        );
+        if let Some(e) = &summary.error {
+            eprintln!("{}: stopped early: {e}", summary.path.display());
+            complete = false;
+        }
+        sent += summary.sent;
+        skipped += summary.skipped;
+    }
+
+    copier.flush().await;
+    let stats = copier.stats();
+    eprintln!(
+        "total: {sent} rows sent, {skipped} skipped, {} copied, {} discarded",
+        stats.rows_copied, stats.rows_discarded
+    );
+    Ok(complete && stats.rows_discarded == 0)
+}
+
+impl Loader {
+    async fn load(&self, path: PathBuf) -> Summary {
+        let mut summary = Summary {
+            path,
+            sent: 0,
+            skipped: 0,
+            error: None,
+        };
+        let format = self.args.format.unwrap_or_else(|| {
+            match summary.path.extension().and_then(|e| e.to_str()) {
+                Some("tsv" | "tab") => Format::Tsv,
+                Some("ndjson" | "jsonl") => Format::Ndjson,
+                _ => Format::Csv,
+            }
+        });
+        let path = summary.path.clone();
+        let result = match format {
+            Format::Ndjson => self.load_ndjson(&path, &mut summary).await,
+            _ => self.load_delimited(&path, format, &mut summary).await,
+        };
+        summary.error = result.err();
+        summary
+    }
+
+    async fn load_delimited(
+        &self,
+        path: &Path,
+        format: Format,
+        summary: &mut Summary,
+    ) -> Result<(), String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| e.to_string())?;
+        let tsv = format == Format::Tsv;
+        let delimiter = self.args.delimiter.unwrap_or(if tsv { '\t' } else { ',' });
+        let delimiter = u8::try_from(delimiter).map_err(|_| "the delimiter must be ASCII")?;
+        let mut reader = AsyncReaderBuilder::new()
+            .delimiter(delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .quoting(!tsv)
+            .create_reader(file);
+        let default_null = if tsv { "\\N" } else { "" };
+        let is_null = |field: &str| match self.args.nulls.is_empty() {
+            true => field == default_null,
+            false => self.args.nulls.iter().any(|n| n == field),
+        };
+
+        let mut records = reader.records();
+        // the column of each field, in table order without header names
+        let mut targets: Vec<Option<usize>> = (0..self.parsers.len()).map(Some).collect();
+        if self.args.header != Header::None {
+            let Some(header) = records.next().await else {
+                return Ok(());
+            };
+            let header = header.map_err(|e| e.to_string())?;
+            if self.args.header == Header::Names {
+                targets = self.targets(header.iter(), summary);
+            }
+        }
+
+        'records: while let Some(record) = records.next().await {
+            let record: StringRecord = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    let line = e.position().map_or(0, |p| p.line());
+                    self.skip(summary, line, &e.to_string());
+                    continue;
+                }
+            };
+            let line = record.position().map_or(0, |p| p.line());
+            if record.len() != targets.len() {
+                let reason = format!("expected {} fields, got {}", targets.len(), record.len());
+                self.skip(summary, line, &reason);
+                continue;
+            }
+            // TSV fields are escaped like Postgres' text format, NULL is matched before unescaping
+            let mut fields = Vec::with_capacity(targets.len());
+            for (target, field) in targets.iter().zip(record.iter()) {
+                let Some(target) = *target else { continue };
+                let text = match field {
+                    field if is_null(field) => None,
+                    field if tsv => match unescape_text(field) {
+                        Ok(text) => Some(text),
+                        Err(reason) => {
+                            self.skip(summary, line, &reason);
+                            continue 'records;
+                        }
+                    },
+                    field => Some(Cow::Borrowed(field)),
+                };
+                fields.push((target, text));
+            }
+            let fields = fields.iter().map(|(i, text)| (*i, text.as_deref()));
+            self.send(fields, summary, line).await;
+        }
+        Ok(())
+    }
+
+    async fn load_ndjson(&self, path: &Path, summary: &mut Summary) -> Result<(), String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut lines = BufReader::new(file).lines();
+        let mut line = 0;
+        while let Some(text) = lines.next_line().await.map_err(|e| e.to_string())? {
+            line += 1;
+            if text.trim().is_empty() {
+                continue;
+            }
+            let object = match serde_json::from_str::<serde_json::Value>(&text) {
+                Ok(serde_json::Value::Object(object)) => object,
+                Ok(_) => {
+                    self.skip(summary, line, "not a JSON object");
+                    continue;
+                }
+                Err(e) => {
+                    self.skip(summary, line, &e.to_string());
+                    continue;
+                }
+            };
+            // strings are parsed as they are, other values from their JSON text
+            let fields = object
+                .iter()
+                .filter_map(|(key, value)| Some((*self.index.get(key)?, value)))
+                .map(|(i, value)| match value {
+                    serde_json::Value::Null => (i, None),
+                    serde_json::Value::String(s) => (i, Some(s.clone())),
+                    other => (i, Some(other.to_string())),
+                })
+                .collect::<Vec<_>>();
+            let fields = fields.iter().map(|(i, text)| (*i, text.as_deref()));
+            self.send(fields, summary, line).await;
+        }
+        Ok(())
+    }
+
+    /// The column of each header field, `None` for fields the table does not have
+    fn targets<'a>(
+        &self,
+        header: impl Iterator<Item = &'a str>,
+        summary: &Summary,
+    ) -> Vec<Option<usize>> {
+        header
+            .map(|name| {
+                let target = self.index.get(name.trim()).copied();
+                if target.is_none() {
+                    eprintln!(
+                        "{}: ignoring field `{name}`, which is not a loaded column",
+                        summary.path.display()
+                    );
+                }
+                target
+            })
+            .collect()
+    }
+
+    /// Parse the fields of one row, by column index, and send it. Columns
+    /// without a field are NULL.
+    async fn send<'a>(
+        &self,
+        fields: impl Iterator<Item = (usize, Option<&'a str>)>,
+        summary: &mut Summary,
+        line: u64,
+    ) {
+        let mut values: Vec<Option<Value>> = (0..self.parsers.len()).map(|_| None).collect();
+        for (i, text) in fields {
+            let Some(text) = text else { continue };
+            match self.parsers[i].parse(text) {
+                Ok(value) => values[i] = Some(value),
+                Err(reason) => {
+                    let column = &self.copier.columns()[i].0;
+                    self.skip(summary, line, &format!("column `{column}`: {reason}"));
+                    return;
+                }
+            }
+        }
+        let refs: Vec<&(dyn ToSql + Sync)> = values
+            .iter()
+            .map(|v| match v {
+                Some(value) => value.as_ref() as &(dyn ToSql + Sync),
+                None => &Null as &(dyn ToSql + Sync),
+            })
+            .collect();
+        match self.copier.send(&refs).await {
+            Ok(()) => summary.sent += 1,
+            Err(e) => self.skip(summary, line, &e.to_string()),
+        }
+    }
+
+    fn skip(&self, summary: &mut Summary, line: u64, reason: &str) {
+        summary.skipped += 1;
+        if summary.skipped as usize <= self.args.report {
+            eprintln!("{}:{line}: skipped, {reason}", summary.path.display());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use bytes::{BufMut, BytesMut};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use rust_decimal::Decimal;
+use tokio_postgres::types::{to_sql_checked, IsNull, Kind, ToSql, Type};
+use uuid::Uuid;
+
+/// A parsed field, ready to be encoded for its column
+pub type Value = Box<dyn ToSql + Sync + Send>;
+
+/// How the text of a field is parsed for its column
+#[derive(Debug, Clone, Copy)]
+pub enum Parser {
+    Bool,
+    Int2,
+    Int4,
+    Int8,
+    Float4,
+    Float8,
+    Numeric,
+    Text,
+    /// labels of an enum type
+    Label,
+    Json,
+    Bytea,
+    Uuid,
+    Inet,
+    Date,
+    Time,
+    Timestamp,
+    Timestamptz,
+}
+
+impl Parser {
+    /// `None` for a column type the loader cannot parse
+    pub fn new(ty: &Type) -> Option<Self> {
+        if let Kind::Enum(_) = ty.kind() {
+            return Some(Self::Label);
+        }
+        Some(match *ty {
+            Type::BOOL => Self::Bool,
+            Type::INT2 => Self::Int2,
+            Type::INT4 => Self::Int4,
+            Type::INT8 => Self::Int8,
+            Type::FLOAT4 => Self::Float4,
+            Type::FLOAT8 => Self::Float8,
+            Type::NUMERIC => Self::Numeric,
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => Self::Text,
+            Type::JSON | Type::JSONB => Self::Json,
+            Type::BYTEA => Self::Bytea,
+            Type::UUID => Self::Uuid,
+            Type::INET => Self::Inet,
+            Type::DATE => Self::Date,
+            Type::TIME => Self::Time,
+            Type::TIMESTAMP => Self::Timestamp,
+            Type::TIMESTAMPTZ => Self::Timestamptz,
+            _ => return None,
+        })
+    }
+
+    /// Parse the text of a non-null field
+    pub fn parse(&self, text: &str) -> Result<Value, String> {
+        let trimmed = text.trim();
+        let invalid = |what: &str| format!("invalid {what}: {text:?}");
+        Ok(match self {
+            Self::Bool => Box::new(match trimmed.to_ascii_lowercase().as_str() {
+                "t" | "true" | "y" | "yes" | "on" | "1" => true,
+                "f" | "false" | "n" | "no" | "off" | "0" => false,
+                _ => return Err(invalid("boolean")),
+            }),
+            Self::Int2 => Box::new(trimmed.parse::<i16>().map_err(|_| invalid("smallint"))?),
+            Self::Int4 => Box::new(trimmed.parse::<i32>().map_err(|_| invalid("integer"))?),
+            Self::Int8 => Box::new(trimmed.parse::<i64>().map_err(|_| invalid("bigint"))?),
+            Self::Float4 => Box::new(trimmed.parse::<f32>().map_err(|_| invalid("real"))?),
+            Self::Float8 => Box::new(trimmed.parse::<f64>().map_err(|_| invalid("double"))?),
+            Self::Numeric => Box::new(
+                Decimal::from_str(trimmed)
+                    .or_else(|_| Decimal::from_scientific(trimmed))
+                    .map_err(|_| invalid("numeric"))?,
+            ),
+            Self::Text => Box::new(text.to_string()),
+            Self::Label => Box::new(Label(text.to_string())),
+            Self::Json => Box::new(
+                serde_json::from_str::<serde_json::Value>(text).map_err(|_| invalid("JSON"))?,
+            ),
+            Self::Bytea => Box::new(match text.strip_prefix("\\x") {
+                Some(hex) => decode_hex(hex).ok_or_else(|| invalid("hex bytea"))?,
+                None => text.as_bytes().to_vec(),
+            }),
+            Self::Uuid => Box::new(Uuid::parse_str(trimmed).map_err(|_| invalid("UUID"))?),
+            Self::Inet => Box::new(trimmed.parse::<IpAddr>().map_err(|_| invalid("address"))?),
+            Self::Date => Box::new(
+                NaiveDate::parse_from_str(trimmed, "%Y-%m-%d").map_err(|_| invalid("date"))?,
+            ),
+            Self::Time => Box::new(
+                NaiveTime::parse_from_str(trimmed, "%H:%M:%S%.f")
+                    .or_else(|_| NaiveTime::parse_from_str(trimmed, "%H:%M"))
+                    .map_err(|_| invalid("time"))?,
+            ),
+            Self::Timestamp => {
+                Box::new(naive_timestamp(trimmed).ok_or_else(|| invalid("timestamp"))?)
+            }
+            // without an offset, a timestamp is taken as UTC
+            Self::Timestamptz => Box::new(
+                DateTime::parse_from_rfc3339(trimmed)
+                    .or_else(|_| DateTime::parse_from_str(trimmed, "%Y-%m-%d %H:%M:%S%.f%#z"))
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+                    .or_else(|| naive_timestamp(trimmed).map(|dt| dt.and_utc()))
+                    .ok_or_else(|| invalid("timestamp"))?,
+            ),
+        })
+    }
+}
+
+/// `2024-01-31 12:00:00`, with a `T` or fractional seconds, or a bare date at midnight
+fn naive_timestamp(text: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+        .or_else(|_| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f"))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .map(|d| d.and_time(NaiveTime::MIN))
+        })
+}
+
+/// Undo the backslash escapes of Postgres' text format: `\\`, `\t`, `\n` and the
+/// other control characters, octal `\123` and hex `\x41` bytes, and a backslash
+/// before any other character, which stands for that character
+pub fn unescape_text(field: &str) -> Result<Cow<'_, str>, String> {
+    if !field.contains('\\') {
+        return Ok(Cow::Borrowed(field));
+    }
+    let mut out = Vec::with_capacity(field.len());
+    let mut bytes = field.bytes().peekable();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let Some(c) = bytes.next() else {
+            out.push(b);
+            break;
+        };
+        // up to `max` digits of a byte in `radix`
+        let mut digits = |first: Option<u8>, radix: u32, max: usize| {
+            let mut value = first.map_or(0, |d| (d - b'0') as u32);
+            let mut n = first.is_some() as usize;
+            while n < max {
+                match bytes.peek().and_then(|&d| (d as char).to_digit(radix)) {
+                    Some(d) => value = value * radix + d,
+                    None => break,
+                }
+                bytes.next();
+                n += 1;
+            }
+            (n > 0).then_some(value as u8)
+        };
+        match c {
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'0'..=b'7' => out.extend(digits(Some(c), 8, 3)),
+            b'x' => match digits(None, 16, 2) {
+                Some(byte) => out.push(byte),
+                None => out.push(b'x'),
+            },
+            _ => out.push(c),
+        }
+    }
+    String::from_utf8(out)
+        .map(Cow::Owned)
+        .map_err(|_| format!("invalid UTF-8 after unescaping: {field:?}"))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// An enum label, sent as its text
+#[derive(Debug)]
+struct Label(String);
+
+impl ToSql for Label {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        out.put_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(ty.kind(), Kind::Enum(_))
+    }
+
+    to_sql_checked!();
+}
+
+/// NULL for a column of any type
+#[derive(Debug)]
+pub struct Null;
+
+impl ToSql for Null {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        _out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
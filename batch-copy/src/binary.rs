@@ -0,0 +1,163 @@
+use std::error::Error;
+
+use bytes::{BufMut, BytesMut};
+use tokio_postgres::types::{IsNull, ToSql, Type};
+
+use crate::BatchCopyRow;
+
+// The binary COPY file format is described in
+// https://www.postgresql.org/docs/current/sql-copy.html (see "Binary Format")
+
+/// PGCOPY signature, flags field and header extension length
+pub(crate) const HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+
+/// A tuple with field count -1 marks the end of the data
+pub(crate) const TRAILER: &[u8] = &(-1_i16).to_be_bytes();
+
+/// Append one row to `buf` as a binary COPY tuple
+pub fn encode_row<R>(row: &R, buf: &mut BytesMut) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    R: BatchCopyRow + ?Sized,
+{
+    let mut values: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(R::TYPES.len());
+    row.fill_copy_refs(&mut values);
+    if values.len() != R::TYPES.len() {
+        return Err(format!(
+            "expected {} values but got {}",
+            R::TYPES.len(),
+            values.len()
+        )
+        .into());
+    }
+
+    encode_values(&values, R::TYPES, buf).map_err(|(_, e)| e)
+}
+
+/// Append `values` of `types` to `buf` as a binary COPY tuple, failing with the
+/// index of the value that cannot be written
+pub(crate) fn encode_values<'a>(
+    values: &[&(dyn ToSql + Sync)],
+    types: impl IntoIterator<Item = &'a Type>,
+    buf: &mut BytesMut,
+) -> Result<(), (usize, Box<dyn Error + Sync + Send>)> {
+    let count = i16::try_from(values.len()).map_err(|e| (0, e.into()))?;
+    buf.put_i16(count);
+    for (i, (value, ty)) in values.iter().zip(types).enumerate() {
+        let idx = buf.len();
+        buf.put_i32(0);
+        let len = match value.to_sql_checked(ty, buf).map_err(|e| (i, e))? {
+            IsNull::Yes => -1,
+            IsNull::No => i32::try_from(buf.len() - idx - 4).map_err(|e| (i, e.into()))?,
+        };
+        buf[idx..idx + 4].copy_from_slice(&len.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Append the binary NUMERIC whose magnitude has the decimal `digits`, the last
+/// `scale` of them after the decimal point; a negative scale appends zeros.
+///
+/// The value is a sign, a weight and base-10000 digits, the first of which is
+/// multiplied by 10000^weight.
+#[cfg(any(feature = "arrow", feature = "bigdecimal"))]
+pub(crate) fn put_numeric(
+    negative: bool,
+    mut digits: String,
+    scale: i64,
+    out: &mut BytesMut,
+) -> Result<(), std::num::TryFromIntError> {
+    // Split the decimal digits at the decimal point
+    let scale = if scale < 0 {
+        digits.push_str(&"0".repeat(scale.unsigned_abs() as usize));
+        0
+    } else {
+        scale as usize
+    };
+    if digits.len() <= scale {
+        digits.insert_str(0, &"0".repeat(scale - digits.len() + 1));
+    }
+    let (int_part, frac_part) = digits.split_at(digits.len() - scale);
+
+    // Regroup into base-10000 digits, padding the integer part on the left
+    // and the fractional part on the right
+    let int_pad = (4 - int_part.len() % 4) % 4;
+    let frac_pad = (4 - frac_part.len() % 4) % 4;
+    let padded = format!(
+        "{}{}{}{}",
+        "0".repeat(int_pad),
+        int_part,
+        frac_part,
+        "0".repeat(frac_pad)
+    );
+    let mut groups: Vec<i16> = padded
+        .as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap().parse().unwrap())
+        .collect();
+    let mut weight = ((int_part.len() + int_pad) / 4) as i32 - 1;
+
+    // Leading and trailing zero groups are implied by the weight and scale
+    let leading = groups.iter().take_while(|g| **g == 0).count();
+    groups.drain(..leading);
+    weight -= leading as i32;
+    while groups.last() == Some(&0) {
+        groups.pop();
+    }
+    if groups.is_empty() {
+        weight = 0;
+    }
+
+    // NUMERIC_NEG, but zero is never negative
+    let sign: u16 = if negative && !groups.is_empty() {
+        0x4000
+    } else {
+        0x0000
+    };
+
+    out.put_i16(i16::try_from(groups.len())?);
+    out.put_i16(i16::try_from(weight)?);
+    out.put_u16(sign);
+    out.put_u16(u16::try_from(scale)?);
+    for group in groups {
+        out.put_i16(group);
+    }
+    Ok(())
+}
+
+/// The raw fields of each tuple in `encoded`, `None` for NULL. A truncated
+/// tuple at the end is left out.
+pub(crate) fn tuples(encoded: &[u8]) -> Vec<Vec<Option<&[u8]>>> {
+    let mut out = vec![];
+    let mut rest = encoded;
+    while let Some((count, tail)) = rest.split_first_chunk::<2>() {
+        rest = tail;
+        let count = i16::from_be_bytes(*count).max(0) as usize;
+        let mut fields = Vec::with_capacity(count);
+        for _ in 0..count {
+            let Some((len, tail)) = rest.split_first_chunk::<4>() else {
+                return out;
+            };
+            rest = tail;
+            let len = i32::from_be_bytes(*len);
+            if len < 0 {
+                fields.push(None);
+            } else {
+                let Some((field, tail)) = rest.split_at_checked(len as usize) else {
+                    return out;
+                };
+                rest = tail;
+                fields.push(Some(field));
+            }
+        }
+        out.push(fields);
+    }
+    out
+}
+
+/// The raw value of column `idx` in each tuple of `encoded`, `None` for NULL
+pub(crate) fn column_values(encoded: &[u8], idx: usize) -> Vec<Option<&[u8]>> {
+    tuples(encoded)
+        .into_iter()
+        .map(|fields| fields.get(idx).copied().flatten())
+        .collect()
+}
@@ -0,0 +1,165 @@
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+use tokio_postgres::types::Type;
+
+use crate::format::CopyFormat;
+use crate::BatchCopyRow;
+
+/// One column of a row, in COPY order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Column {
+    pub name: &'static str,
+    /// The column type as written in the generated DDL, e.g. `NUMERIC(18,6)`
+    pub sql_type: &'static str,
+    /// `Option<T>` fields may write NULL
+    pub nullable: bool,
+    /// `#[batch_copy(primary_key)]`
+    pub primary_key: bool,
+    /// `#[batch_copy(conflict_key)]`, the upsert conflict target
+    pub conflict_key: bool,
+    /// `#[batch_copy(replace_key)]`, identifies the rows a `WriteMode::Replace` batch replaces
+    pub replace_key: bool,
+    /// overwritten on conflict, unless marked `#[batch_copy(no_update)]`
+    pub update: bool,
+}
+
+/// The table, columns and statements of a row type, as a value
+#[derive(Debug, Clone, Copy)]
+pub struct TableSchema {
+    pub table: &'static str,
+    pub types: &'static [Type],
+    pub columns: &'static [Column],
+    pub copy_statement: &'static str,
+    pub copy_format: CopyFormat,
+    pub check_statement: &'static str,
+    pub ddl_statement: &'static str,
+}
+
+impl TableSchema {
+    pub const fn of<T: BatchCopyRow + ?Sized>() -> Self {
+        Self {
+            table: T::TABLE,
+            types: T::TYPES,
+            columns: T::COLUMNS,
+            copy_statement: T::COPY_STATEMENT,
+            copy_format: T::COPY_FORMAT,
+            check_statement: T::CHECK_STATEMENT,
+            ddl_statement: T::DDL_STATEMENT,
+        }
+    }
+}
+
+/// Column fragments of a `#[derive(BatchCopy)]` struct, spliced into a parent
+/// row by `#[batch_copy(flatten)]`.
+pub trait ColumnGroup {
+    /// Comma separated column names, e.g. `host, region`
+    const COLUMN_LIST: &'static str;
+    /// One `CREATE TABLE` column definition per line
+    const COLUMN_DEFS: &'static str;
+    /// `, col` for each primary key column, e.g. `, host, region`, or empty
+    const PRIMARY_KEY: &'static str;
+}
+
+/// A `PRIMARY_KEY` list without its leading `, `. An empty list stays empty,
+/// since the constant is evaluated even for a row without a key.
+#[doc(hidden)]
+pub const fn strip_list_sep(list: &str) -> &str {
+    if list.is_empty() {
+        return list;
+    }
+    let (_, rest) = list.as_bytes().split_at(2);
+    match std::str::from_utf8(rest) {
+        Ok(rest) => rest,
+        Err(_) => panic!("a primary key list starts with `, `"),
+    }
+}
+
+/// Whether a `PRIMARY_KEY` list such as `, host, region` names `column`, used by
+/// `#[derive(BatchCopy)]` to check that the key of a partitioned row with
+/// flattened fields includes the partition key.
+#[doc(hidden)]
+pub const fn has_list_item(list: &str, column: &str) -> bool {
+    let (list, column) = (list.as_bytes(), column.as_bytes());
+    let mut start = 0;
+    while start < list.len() {
+        // skip the `, ` before each item
+        start += 2;
+        let mut end = start;
+        while end < list.len() && list[end] != b',' {
+            end += 1;
+        }
+        if end - start == column.len() {
+            let mut i = 0;
+            while i < column.len() && list[start + i] == column[i] {
+                i += 1;
+            }
+            if i == column.len() {
+                return true;
+            }
+        }
+        start = end;
+    }
+    false
+}
+
+/// The slices a row with flattened fields concatenates into its `TYPES` or
+/// `COLUMNS`, implemented by `#[derive(BatchCopy)]` for `Concat` to join.
+#[doc(hidden)]
+pub trait ConcatParts<T: 'static> {
+    const PARTS: &'static [&'static [T]];
+}
+
+/// The concatenation of `P::PARTS`, `N` elements long, as the constant `ARRAY`.
+///
+/// Used by `#[derive(BatchCopy)]` so that the generated code has no `unsafe`
+/// block and compiles under `#![forbid(unsafe_code)]`. The parts are constants,
+/// so it is safe for any `T`:
+///
+/// ```
+/// use batch_copy::__private::{Concat, ConcatParts};
+///
+/// struct Words;
+/// impl ConcatParts<String> for Words {
+///     const PARTS: &'static [&'static [String]] = &[&[String::new()], &[String::new()]];
+/// }
+/// assert_eq!(Concat::<String, Words, 2>::ARRAY.len(), 2);
+/// ```
+///
+/// Values built at runtime, which a bitwise copy could free twice, cannot be
+/// among the parts:
+///
+/// ```compile_fail
+/// use batch_copy::__private::ConcatParts;
+///
+/// struct Words;
+/// impl ConcatParts<String> for Words {
+///     const PARTS: &'static [&'static [String]] = &[&[String::from("heap")]];
+/// }
+/// ```
+#[doc(hidden)]
+pub struct Concat<T, P: ?Sized, const N: usize>(PhantomData<fn(&P) -> T>);
+
+impl<T: 'static, P: ConcatParts<T> + ?Sized, const N: usize> Concat<T, P, N> {
+    pub const ARRAY: [T; N] = {
+        let parts = P::PARTS;
+        let mut out: [MaybeUninit<T>; N] = [const { MaybeUninit::uninit() }; N];
+        let mut n = 0;
+        let mut i = 0;
+        while i < parts.len() {
+            let mut j = 0;
+            while j < parts[i].len() {
+                assert!(n < N, "Concat is shorter than its parts");
+                // Safety: the element is a constant, which is copied bitwise on
+                // every use anyway, and so is the array this one goes into
+                out[n] = MaybeUninit::new(unsafe { std::ptr::read(&parts[i][j]) });
+                n += 1;
+                j += 1;
+            }
+            i += 1;
+        }
+        assert!(n == N, "Concat is longer than its parts");
+        // Safety: all N elements were written above
+        unsafe { std::ptr::read(&out as *const [MaybeUninit<T>; N] as *const [T; N]) }
+    };
+}
@@ -12,6 +12,58 @@ pub enum BatchCopyDatabaseError {
         source: tokio_postgres::Error,
         ddl: String,
     },
+    #[error(
+        "Table schema does not match the row:\n{}\n\nHint — the row expects:\n{ddl}",
+        .mismatches.iter().map(|m| format!("  {m}")).collect::<Vec<_>>().join("\n")
+    )]
+    SchemaMismatch {
+        mismatches: Vec<ColumnMismatch>,
+        ddl: String,
+    },
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(&'static str),
+    #[error("Invalid table name {0:?}, expected an identifier or schema.identifier")]
+    InvalidTableName(String),
+    #[error("Invalid PGCOPY file: {0}")]
+    InvalidCopyFile(String),
+    #[error("Cannot write row {row} of column `{column}`: {reason}")]
+    InvalidValue {
+        column: String,
+        row: usize,
+        reason: String,
+    },
+    #[error("{0} batches failed since the last swap, the snapshot was not swapped in")]
+    IncompleteSnapshot(u64),
+    #[error(
+        "Cannot swap the snapshot of {table}, these objects depend on it: {}",
+        .dependents.join(", ")
+    )]
+    SnapshotDependents {
+        table: String,
+        dependents: Vec<String>,
+    },
+    #[error("{0} batches failed to copy, their rows were discarded")]
+    FailedBatches(u64),
+    #[error("No column type for `{column}`, of Arrow type {data_type}")]
+    UnsupportedType { column: String, data_type: String },
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error")]
+    Parquet(#[from] parquet::errors::ParquetError),
     #[error("unknown data store error")]
     Unknown,
 }
+
+/// A column whose table definition cannot accept the row's values
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ColumnMismatch {
+    #[error("column `{column}`: table has type {table_type} but the row writes {row_type}")]
+    Type {
+        column: String,
+        table_type: String,
+        row_type: String,
+    },
+    #[error("column `{column}`: table column is NOT NULL but the row field is optional")]
+    Nullability { column: String },
+}
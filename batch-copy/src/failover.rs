@@ -0,0 +1,138 @@
+use std::error::Error;
+use std::time::Duration;
+
+use bb8::{PooledConnection, RunError};
+use bb8_postgres::PostgresConnectionManager;
+use thiserror::Error;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{Client, NoTls};
+
+use crate::errors::BatchCopyDatabaseError;
+use crate::handler::{Configuration, Pool, PoolSettings};
+
+/// Server states that end a session when the primary goes away or is demoted
+const FAILOVER_STATES: &[SqlState] = &[
+    SqlState::READ_ONLY_SQL_TRANSACTION,
+    SqlState::ADMIN_SHUTDOWN,
+    SqlState::CRASH_SHUTDOWN,
+    SqlState::CANNOT_CONNECT_NOW,
+];
+
+#[derive(Error, Debug)]
+pub(crate) enum FlushError {
+    #[error("the server is a standby, pg_is_in_recovery() is true")]
+    Standby,
+    /// never retried, the server may have applied the COMMIT before failing
+    #[error("COMMIT failed, the batch may or may not have been written: {0}")]
+    Commit(#[source] tokio_postgres::Error),
+}
+
+/// How the actor gets an interrupted batch to the new primary
+pub(crate) struct Failover {
+    /// rebuilds the pool, whose new connections only go to a primary
+    pub(crate) pool: PoolSettings,
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+    /// check that the server is still the primary before the next batch
+    recheck: bool,
+}
+
+impl Failover {
+    pub(crate) fn new(pool: PoolSettings, cfg: &Configuration) -> Self {
+        Self {
+            pool,
+            retries: cfg.failover_retries,
+            backoff: Duration::from_millis(cfg.failover_backoff_ms),
+            recheck: false,
+        }
+    }
+
+    /// Fail with `FlushError::Standby` if a batch failed since the last check
+    /// and the server has become a standby. New connections need no check, the
+    /// pool only connects to a primary.
+    pub(crate) async fn check(
+        &mut self,
+        client: &Client,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        if !self.recheck {
+            return Ok(());
+        }
+        let standby: bool = client
+            .query_one("SELECT pg_is_in_recovery()", &[])
+            .await?
+            .get(0);
+        if standby {
+            return Err(FlushError::Standby.into());
+        }
+        self.recheck = false;
+        Ok(())
+    }
+
+    /// Whether to write a batch again after `error`, rebuilding `pool` first
+    pub(crate) async fn retry(
+        &mut self,
+        pool: &mut Pool,
+        error: &(dyn Error + Sync + Send + 'static),
+        attempt: u32,
+    ) -> bool {
+        self.recheck = true;
+        if attempt >= self.retries || !is_failover(error) {
+            return false;
+        }
+        log::warn!(
+            "batch interrupted, reconnecting to the primary (retry {} of {}):\n\t{error}",
+            attempt + 1,
+            self.retries
+        );
+        tokio::time::sleep(self.backoff).await;
+        // drop every pooled connection, they may still point at the old primary
+        match self.pool.build().await {
+            Ok(new) => *pool = new,
+            Err(e) => log::warn!("could not rebuild the pool:\n\t{e}"),
+        }
+        true
+    }
+}
+
+/// Check out a connection for a batch. When the pool times out, the error of a
+/// fresh connection attempt is returned instead, to tell a server that went
+/// away from a pool that is merely busy.
+pub(crate) async fn checkout(
+    pool: &Pool,
+) -> Result<PooledConnection<'static, PostgresConnectionManager<NoTls>>, Box<dyn Error + Sync + Send>>
+{
+    match pool.get_owned().await {
+        Ok(connection) => Ok(connection),
+        Err(RunError::User(e)) => Err(e.into()),
+        Err(RunError::TimedOut) => match pool.dedicated_connection().await {
+            Err(e) => Err(e.into()),
+            Ok(_) => Err(RunError::<tokio_postgres::Error>::TimedOut.into()),
+        },
+    }
+}
+
+/// Whether a batch failed because the server went away or stopped taking writes,
+/// so that it can be retried on a fresh connection
+pub(crate) fn is_failover(e: &(dyn Error + 'static)) -> bool {
+    if let Some(e) = e.downcast_ref::<FlushError>() {
+        return matches!(e, FlushError::Standby);
+    }
+    if let Some(RunError::User(e)) = e.downcast_ref::<RunError<tokio_postgres::Error>>() {
+        return is_connection_lost(e);
+    }
+    // preparing a table wraps the server's error
+    if let Some(e) = e.downcast_ref::<BatchCopyDatabaseError>() {
+        return e.source().is_some_and(is_failover);
+    }
+    e.downcast_ref::<tokio_postgres::Error>()
+        .is_some_and(is_connection_lost)
+}
+
+/// The connection was refused or dropped, or the server is shutting down or
+/// refusing writes
+fn is_connection_lost(e: &tokio_postgres::Error) -> bool {
+    e.is_closed()
+        || e.code().is_some_and(|code| FAILOVER_STATES.contains(code))
+        || e.source()
+            .is_some_and(|source| source.is::<std::io::Error>())
+}
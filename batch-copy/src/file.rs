@@ -0,0 +1,151 @@
+use std::error::Error;
+use std::marker::PhantomData;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::binary::{encode_row, HEADER, TRAILER};
+use crate::errors::BatchCopyDatabaseError;
+use crate::BatchCopyRow;
+
+/// The PGCOPY signature that starts `HEADER`
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+/// Header flag set when every tuple carries an OID, which rows never have
+const HAS_OIDS: u32 = 1 << 16;
+
+/// Write the buffered tuples out once they reach about this size
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The longest field Postgres stores, 1 GiB less a byte
+const MAX_FIELD_LEN: i32 = (1 << 30) - 1;
+
+/// Writes rows as a PGCOPY binary file rather than to a database.
+///
+/// The output is what `Copier` sends over `COPY ... FROM STDIN (FORMAT binary)`,
+/// so it loads with `COPY table (columns) FROM 'file' (FORMAT binary)` or
+/// psql's `\copy`. Nothing is complete until `finish` writes the trailer.
+pub struct CopyFileWriter<T, W> {
+    writer: W,
+    buf: BytesMut,
+    rows: u64,
+    row_type: PhantomData<fn(&T)>,
+}
+
+impl<T, W> CopyFileWriter<T, W>
+where
+    T: BatchCopyRow,
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(writer: W) -> Self {
+        let mut buf = BytesMut::with_capacity(CHUNK_SIZE * 2);
+        buf.put_slice(HEADER);
+        Self {
+            writer,
+            buf,
+            rows: 0,
+            row_type: PhantomData,
+        }
+    }
+
+    /// Append a row. A row that fails to encode is not written.
+    pub async fn write(&mut self, row: &T) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let len = self.buf.len();
+        if let Err(e) = encode_row(row, &mut self.buf) {
+            self.buf.truncate(len);
+            return Err(e);
+        }
+        self.rows += 1;
+        if self.buf.len() > CHUNK_SIZE {
+            self.writer.write_all(&self.buf.split()).await?;
+        }
+        Ok(())
+    }
+
+    /// Rows written so far
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Write the trailer, flush, and hand back the writer
+    pub async fn finish(mut self) -> Result<W, Box<dyn Error + Sync + Send>> {
+        self.buf.put_slice(TRAILER);
+        self.writer.write_all(&self.buf).await?;
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the tuples of a PGCOPY binary file, checking each against the row's columns
+pub(crate) struct CopyFileReader<R> {
+    reader: R,
+    columns: usize,
+    tuples: u64,
+}
+
+impl<R> CopyFileReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Check the file header, skipping any header extension
+    pub(crate) async fn new(mut reader: R, columns: usize) -> Result<Self, BatchCopyDatabaseError> {
+        let mut signature = [0; SIGNATURE.len()];
+        reader.read_exact(&mut signature).await?;
+        if signature != SIGNATURE {
+            return Err(invalid("missing the PGCOPY signature"));
+        }
+        if reader.read_u32().await? & HAS_OIDS != 0 {
+            return Err(invalid("tuples with OIDs are not supported"));
+        }
+        let extension = reader.read_u32().await?;
+        tokio::io::copy(
+            &mut (&mut reader).take(extension as u64),
+            &mut tokio::io::sink(),
+        )
+        .await?;
+        Ok(Self {
+            reader,
+            columns,
+            tuples: 0,
+        })
+    }
+
+    /// The next tuple as it is sent to COPY, `None` at the trailer
+    pub(crate) async fn next_tuple(&mut self) -> Result<Option<Bytes>, BatchCopyDatabaseError> {
+        let count = self.reader.read_i16().await?;
+        if count == -1 {
+            return Ok(None);
+        }
+        self.tuples += 1;
+        if count as usize != self.columns {
+            return Err(invalid(format!(
+                "tuple {} has {count} fields but the row has {} columns",
+                self.tuples, self.columns
+            )));
+        }
+
+        let mut tuple = BytesMut::new();
+        tuple.put_i16(count);
+        for _ in 0..count {
+            let len = self.reader.read_i32().await?;
+            if !(-1..=MAX_FIELD_LEN).contains(&len) {
+                return Err(invalid(format!(
+                    "tuple {} has a field of length {len}",
+                    self.tuples
+                )));
+            }
+            tuple.put_i32(len);
+            // read as it arrives rather than allocating the whole length of a
+            // file that may be truncated
+            let mut field = (&mut self.reader).take(len.max(0) as u64);
+            while field.limit() > 0 {
+                if field.read_buf(&mut tuple).await? == 0 {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+        Ok(Some(tuple.freeze()))
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> BatchCopyDatabaseError {
+    BatchCopyDatabaseError::InvalidCopyFile(reason.into())
+}
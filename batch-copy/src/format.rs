@@ -0,0 +1,676 @@
+use std::error::Error;
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bytes::{BufMut, BytesMut};
+use tokio_postgres::types::{Kind, Type};
+
+use crate::binary::tuples;
+use crate::partition::{civil_from_days, MICROS_PER_DAY, PG_EPOCH_DAYS};
+
+type BoxError = Box<dyn Error + Sync + Send>;
+
+/// The data format of the COPY sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CopyFormat {
+    /// PGCOPY binary, the fastest and the most exact
+    #[default]
+    Binary,
+    /// tab separated text, `\N` for NULL
+    Text,
+    /// comma separated values, every value quoted and an unquoted empty field for NULL
+    Csv,
+}
+
+impl CopyFormat {
+    /// The option of `COPY ... FROM STDIN (FORMAT ...)`
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Binary => "binary",
+            Self::Text => "text",
+            Self::Csv => "csv",
+        }
+    }
+
+    /// Rewrite a generated `COPY ... FROM STDIN (FORMAT binary)` for this format
+    pub(crate) fn copy_statement(self, statement: &str) -> String {
+        match statement.rsplit_once(" (FORMAT ") {
+            Some((copy, _)) => format!("{copy} (FORMAT {})", self.name()),
+            None => statement.to_string(),
+        }
+    }
+
+    /// Append the binary COPY tuples in `encoded` to `out` in this format
+    pub(crate) fn write_tuples(
+        self,
+        types: &[Type],
+        encoded: &[u8],
+        out: &mut BytesMut,
+    ) -> Result<(), BoxError> {
+        if self == Self::Binary {
+            out.extend_from_slice(encoded);
+            return Ok(());
+        }
+
+        let mut text = String::new();
+        for fields in tuples(encoded) {
+            for (i, (field, ty)) in fields.iter().zip(types).enumerate() {
+                if i > 0 {
+                    out.put_u8(if self == Self::Csv { b',' } else { b'\t' });
+                }
+                let Some(raw) = field else {
+                    if self == Self::Text {
+                        out.put_slice(b"\\N");
+                    }
+                    continue;
+                };
+                text.clear();
+                write_value(ty, raw, &mut text)?;
+                match self {
+                    Self::Text => escape_text(&text, out),
+                    _ => quote_csv(&text, out),
+                }
+            }
+            out.put_u8(b'\n');
+        }
+        Ok(())
+    }
+}
+
+/// Whether values of `ty` can be written in the text and CSV formats
+pub fn supports_text(ty: &Type) -> bool {
+    match ty.kind() {
+        Kind::Enum(_) => true,
+        Kind::Array(element) => supports_text(element),
+        Kind::Range(subtype) => supports_text(subtype),
+        Kind::Domain(base) => supports_text(base),
+        _ => matches!(
+            *ty,
+            Type::BOOL
+                | Type::INT2
+                | Type::INT4
+                | Type::INT8
+                | Type::OID
+                | Type::FLOAT4
+                | Type::FLOAT8
+                | Type::NUMERIC
+                | Type::TEXT
+                | Type::VARCHAR
+                | Type::BPCHAR
+                | Type::NAME
+                | Type::JSON
+                | Type::JSONB
+                | Type::BYTEA
+                | Type::UUID
+                | Type::DATE
+                | Type::TIME
+                | Type::TIMESTAMP
+                | Type::TIMESTAMPTZ
+                | Type::INTERVAL
+                | Type::INET
+                | Type::CIDR
+                | Type::MACADDR
+                | Type::POINT
+                | Type::BOX
+                | Type::PATH
+        ),
+    }
+}
+
+/// Escape a value for the text format: backslash and the control characters
+/// that would end the field or the line
+fn escape_text(text: &str, out: &mut BytesMut) {
+    for b in text.bytes() {
+        match b {
+            b'\\' => out.put_slice(b"\\\\"),
+            b'\n' => out.put_slice(b"\\n"),
+            b'\r' => out.put_slice(b"\\r"),
+            b'\t' => out.put_slice(b"\\t"),
+            0x08 => out.put_slice(b"\\b"),
+            0x0b => out.put_slice(b"\\v"),
+            0x0c => out.put_slice(b"\\f"),
+            b => out.put_u8(b),
+        }
+    }
+}
+
+/// Quote a CSV value, so that an empty string stays distinct from NULL
+fn quote_csv(text: &str, out: &mut BytesMut) {
+    out.put_u8(b'"');
+    for b in text.bytes() {
+        if b == b'"' {
+            out.put_u8(b'"');
+        }
+        out.put_u8(b);
+    }
+    out.put_u8(b'"');
+}
+
+/// Reads big-endian values off a binary field
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BoxError> {
+        let (head, tail) = self.0.split_at_checked(n).ok_or("truncated binary value")?;
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BoxError> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8, BoxError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn i16(&mut self) -> Result<i16, BoxError> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, BoxError> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, BoxError> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Result<f64, BoxError> {
+        Ok(f64::from_be_bytes(self.array()?))
+    }
+
+    /// A length-prefixed value inside an array or range, `None` for NULL
+    fn value(&mut self) -> Result<Option<&'a [u8]>, BoxError> {
+        match self.i32()? {
+            len if len < 0 => Ok(None),
+            len => Ok(Some(self.take(len as usize)?)),
+        }
+    }
+}
+
+/// Write the text form of a binary value of type `ty`, as the type's input function reads it
+fn write_value(ty: &Type, raw: &[u8], out: &mut String) -> Result<(), BoxError> {
+    match ty.kind() {
+        Kind::Enum(_) => {
+            out.push_str(std::str::from_utf8(raw)?);
+            return Ok(());
+        }
+        Kind::Array(element) => return write_array(element, raw, out),
+        Kind::Range(subtype) => return write_range(subtype, raw, out),
+        Kind::Domain(base) => return write_value(base, raw, out),
+        _ => {}
+    }
+
+    let mut f = Fields(raw);
+    match *ty {
+        Type::BOOL => out.push(if f.u8()? != 0 { 't' } else { 'f' }),
+        Type::INT2 => write!(out, "{}", f.i16()?)?,
+        Type::INT4 => write!(out, "{}", f.i32()?)?,
+        Type::INT8 => write!(out, "{}", f.i64()?)?,
+        Type::OID => write!(out, "{}", u32::from_be_bytes(f.array()?))?,
+        Type::FLOAT4 => write_float(f32::from_be_bytes(f.array()?) as f64, out),
+        Type::FLOAT8 => write_float(f.f64()?, out),
+        Type::NUMERIC => write_numeric(&mut f, out)?,
+        Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME | Type::JSON => {
+            out.push_str(std::str::from_utf8(raw)?)
+        }
+        Type::JSONB => {
+            // a version byte, then the JSON text
+            f.u8()?;
+            out.push_str(std::str::from_utf8(f.0)?)
+        }
+        Type::BYTEA => {
+            out.push_str("\\x");
+            for b in raw {
+                write!(out, "{b:02x}")?;
+            }
+        }
+        Type::UUID => {
+            for (i, b) in f.array::<16>()?.iter().enumerate() {
+                if matches!(i, 4 | 6 | 8 | 10) {
+                    out.push('-');
+                }
+                write!(out, "{b:02x}")?;
+            }
+        }
+        Type::DATE => match f.i32()? {
+            i32::MAX => out.push_str("infinity"),
+            i32::MIN => out.push_str("-infinity"),
+            days => {
+                let bc = write_date(days as i64, out)?;
+                if bc {
+                    out.push_str(" BC");
+                }
+            }
+        },
+        Type::TIME => write_time(f.i64()?, out)?,
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => match f.i64()? {
+            i64::MAX => out.push_str("infinity"),
+            i64::MIN => out.push_str("-infinity"),
+            micros => {
+                let bc = write_date(micros.div_euclid(MICROS_PER_DAY), out)?;
+                out.push(' ');
+                write_time(micros.rem_euclid(MICROS_PER_DAY), out)?;
+                if *ty == Type::TIMESTAMPTZ {
+                    out.push_str("+00");
+                }
+                if bc {
+                    out.push_str(" BC");
+                }
+            }
+        },
+        Type::INTERVAL => {
+            let micros = f.i64()?;
+            let days = f.i32()?;
+            let months = f.i32()?;
+            write!(out, "{months} mons {days} days {micros} microseconds")?;
+        }
+        Type::INET | Type::CIDR => {
+            let family = f.u8()?;
+            let bits = f.u8()?;
+            let _is_cidr = f.u8()?;
+            let len = f.u8()?;
+            match (family, len) {
+                (2, 4) => write!(out, "{}/{bits}", Ipv4Addr::from(f.array::<4>()?))?,
+                (3, 16) => write!(out, "{}/{bits}", Ipv6Addr::from(f.array::<16>()?))?,
+                _ => return Err("unknown inet address family".into()),
+            }
+        }
+        Type::MACADDR => {
+            for (i, b) in f.array::<6>()?.iter().enumerate() {
+                if i > 0 {
+                    out.push(':');
+                }
+                write!(out, "{b:02x}")?;
+            }
+        }
+        Type::POINT => write_point(&mut f, out)?,
+        Type::BOX => {
+            write_point(&mut f, out)?;
+            out.push(',');
+            write_point(&mut f, out)?;
+        }
+        Type::PATH => {
+            let closed = f.u8()? != 0;
+            let points = f.i32()?;
+            out.push(if closed { '(' } else { '[' });
+            for i in 0..points {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_point(&mut f, out)?;
+            }
+            out.push(if closed { ')' } else { ']' });
+        }
+        _ => return Err(format!("type {ty} has no text encoding, use CopyFormat::Binary").into()),
+    }
+    Ok(())
+}
+
+/// Shortest text that reads back as the same float
+fn write_float(v: f64, out: &mut String) {
+    if v.is_nan() {
+        out.push_str("NaN");
+    } else if v.is_infinite() {
+        out.push_str(if v > 0.0 { "Infinity" } else { "-Infinity" });
+    } else {
+        let _ = write!(out, "{v}");
+    }
+}
+
+/// Write a date given in days since 2000-01-01 without its era, returning
+/// whether it is BC
+fn write_date(days: i64, out: &mut String) -> Result<bool, BoxError> {
+    let (y, m, d) = civil_from_days(days + PG_EPOCH_DAYS);
+    // there is no year 0, 1 BC comes right before 1 AD
+    let (year, bc) = if y <= 0 { (1 - y, true) } else { (y, false) };
+    write!(out, "{year:04}-{m:02}-{d:02}")?;
+    Ok(bc)
+}
+
+fn write_time(micros: i64, out: &mut String) -> Result<(), BoxError> {
+    let secs = micros / 1_000_000;
+    write!(
+        out,
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )?;
+    if micros % 1_000_000 != 0 {
+        write!(out, ".{:06}", micros % 1_000_000)?;
+    }
+    Ok(())
+}
+
+fn write_point(f: &mut Fields, out: &mut String) -> Result<(), BoxError> {
+    out.push('(');
+    write_float(f.f64()?, out);
+    out.push(',');
+    write_float(f.f64()?, out);
+    out.push(')');
+    Ok(())
+}
+
+/// NUMERIC is a sign, a weight and base-10000 digits, the first of which is
+/// multiplied by 10000^weight
+fn write_numeric(f: &mut Fields, out: &mut String) -> Result<(), BoxError> {
+    let ndigits = f.i16()?.max(0) as usize;
+    let weight = f.i16()? as i64;
+    let sign = u16::from_be_bytes(f.array()?);
+    let dscale = u16::from_be_bytes(f.array()?) as usize;
+    let digits = (0..ndigits)
+        .map(|_| f.i16())
+        .collect::<Result<Vec<_>, _>>()?;
+    match sign {
+        0xC000 => {
+            out.push_str("NaN");
+            return Ok(());
+        }
+        0xD000 => {
+            out.push_str("Infinity");
+            return Ok(());
+        }
+        0xF000 => {
+            out.push_str("-Infinity");
+            return Ok(());
+        }
+        0x4000 => out.push('-'),
+        _ => {}
+    }
+
+    let digit = |i: i64| {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| digits.get(i))
+            .copied()
+            .unwrap_or(0)
+    };
+    if weight < 0 {
+        out.push('0');
+    } else {
+        write!(out, "{}", digit(0))?;
+        for i in 1..=weight {
+            write!(out, "{:04}", digit(i))?;
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::with_capacity(dscale + 4);
+        let mut i = weight + 1;
+        while fraction.len() < dscale {
+            write!(fraction, "{:04}", digit(i))?;
+            i += 1;
+        }
+        fraction.truncate(dscale);
+        out.push('.');
+        out.push_str(&fraction);
+    }
+    Ok(())
+}
+
+/// Arrays are written as nested braces, e.g. `{{1,2},{3,NULL}}`
+fn write_array(element: &Type, raw: &[u8], out: &mut String) -> Result<(), BoxError> {
+    let mut f = Fields(raw);
+    let ndim = f.i32()?.max(0) as usize;
+    let _has_nulls = f.i32()?;
+    let _element_oid = f.i32()?;
+    let mut dims = Vec::with_capacity(ndim);
+    for _ in 0..ndim {
+        let len = f.i32()?;
+        let lower = f.i32()?;
+        dims.push((len.max(0) as usize, lower));
+    }
+    if dims.is_empty() {
+        out.push_str("{}");
+        return Ok(());
+    }
+    // bounds other than the default of 1 are spelled out in front, e.g. `[0:1]={a,b}`
+    if dims.iter().any(|(_, lower)| *lower != 1) {
+        for (len, lower) in &dims {
+            write!(out, "[{lower}:{}]", *lower as i64 + *len as i64 - 1)?;
+        }
+        out.push('=');
+    }
+    let delimiter = if *element == Type::BOX { ';' } else { ',' };
+    write_array_dim(element, delimiter, &dims, &mut f, out)
+}
+
+fn write_array_dim(
+    element: &Type,
+    delimiter: char,
+    dims: &[(usize, i32)],
+    f: &mut Fields,
+    out: &mut String,
+) -> Result<(), BoxError> {
+    out.push('{');
+    for i in 0..dims[0].0 {
+        if i > 0 {
+            out.push(delimiter);
+        }
+        if dims.len() > 1 {
+            write_array_dim(element, delimiter, &dims[1..], f, out)?;
+            continue;
+        }
+        match f.value()? {
+            None => out.push_str("NULL"),
+            Some(raw) => {
+                let mut text = String::new();
+                write_value(element, raw, &mut text)?;
+                let quote = text.is_empty()
+                    || text.eq_ignore_ascii_case("NULL")
+                    || text.chars().any(|c| {
+                        matches!(c, '{' | '}' | '"' | '\\') || c == delimiter || c.is_whitespace()
+                    });
+                write_quoted(&text, quote, out);
+            }
+        }
+    }
+    out.push('}');
+    Ok(())
+}
+
+/// Ranges are written as bounds in brackets, e.g. `[1,10)`, or `empty`
+fn write_range(subtype: &Type, raw: &[u8], out: &mut String) -> Result<(), BoxError> {
+    const EMPTY: u8 = 0x01;
+    const LOWER_INCLUSIVE: u8 = 0x02;
+    const UPPER_INCLUSIVE: u8 = 0x04;
+    const LOWER_INFINITE: u8 = 0x08;
+    const UPPER_INFINITE: u8 = 0x10;
+
+    let mut f = Fields(raw);
+    let flags = f.u8()?;
+    if flags & EMPTY != 0 {
+        out.push_str("empty");
+        return Ok(());
+    }
+    out.push(if flags & LOWER_INCLUSIVE != 0 {
+        '['
+    } else {
+        '('
+    });
+    for (i, infinite) in [LOWER_INFINITE, UPPER_INFINITE].into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        if flags & infinite != 0 {
+            continue;
+        }
+        let raw = f.value()?.ok_or("NULL range bound")?;
+        let mut text = String::new();
+        write_value(subtype, raw, &mut text)?;
+        let quote = text.is_empty()
+            || text.chars().any(|c| {
+                matches!(c, ',' | '(' | ')' | '[' | ']' | '"' | '\\') || c.is_whitespace()
+            });
+        write_quoted(&text, quote, out);
+    }
+    out.push(if flags & UPPER_INCLUSIVE != 0 {
+        ']'
+    } else {
+        ')'
+    });
+    Ok(())
+}
+
+/// An array element or range bound, in double quotes with `"` and `\` escaped if `quote`
+fn write_quoted(text: &str, quote: bool, out: &mut String) {
+    if !quote {
+        out.push_str(text);
+        return;
+    }
+    out.push('"');
+    for c in text.chars() {
+        if matches!(c, '"' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::types::ToSql;
+
+    use super::*;
+    use crate::binary::encode_values;
+    use crate::range::PgRange;
+
+    /// The binary form of `value` as a `ty`
+    fn binary(ty: &Type, value: &(dyn ToSql + Sync)) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        value.to_sql_checked(ty, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn text(ty: &Type, raw: &[u8]) -> String {
+        let mut out = String::new();
+        write_value(ty, raw, &mut out).unwrap();
+        out
+    }
+
+    /// One row of `values` in `format`
+    fn row(format: CopyFormat, types: &[Type], values: &[&(dyn ToSql + Sync)]) -> String {
+        let mut encoded = BytesMut::new();
+        encode_values(values, types, &mut encoded).unwrap();
+        let mut out = BytesMut::new();
+        format.write_tuples(types, &encoded, &mut out).unwrap();
+        String::from_utf8(out.to_vec()).unwrap()
+    }
+
+    fn numeric(weight: i16, sign: u16, dscale: u16, digits: &[i16]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_i16(digits.len() as i16);
+        buf.put_i16(weight);
+        buf.put_u16(sign);
+        buf.put_u16(dscale);
+        for digit in digits {
+            buf.put_i16(*digit);
+        }
+        buf.to_vec()
+    }
+
+    #[test]
+    fn escape_text_controls() {
+        let mut out = BytesMut::new();
+        escape_text("a\\b\tc\nd\re\x08\x0b\x0c", &mut out);
+        assert_eq!(&out[..], b"a\\\\b\\tc\\nd\\re\\b\\v\\f");
+
+        // the string `\N` stays distinct from NULL
+        let types = [Type::TEXT, Type::TEXT];
+        let line = row(CopyFormat::Text, &types, &[&"\\N", &None::<&str>]);
+        assert_eq!(line, "\\\\N\t\\N\n");
+    }
+
+    #[test]
+    fn quote_csv_values() {
+        let mut out = BytesMut::new();
+        quote_csv("say \"hi\", ok\nbye", &mut out);
+        assert_eq!(&out[..], b"\"say \"\"hi\"\", ok\nbye\"");
+
+        // an empty string is quoted, NULL is an empty field
+        let types = [Type::TEXT, Type::TEXT, Type::INT4];
+        let line = row(CopyFormat::Csv, &types, &[&"", &None::<&str>, &7]);
+        assert_eq!(line, "\"\",,\"7\"\n");
+    }
+
+    #[test]
+    fn numeric_scale_and_sign() {
+        let cases = [
+            (numeric(0, 0, 2, &[123, 4500]), "123.45"),
+            (numeric(0, 0, 2, &[1, 5000]), "1.50"),
+            (numeric(-1, 0x4000, 3, &[50]), "-0.005"),
+            (numeric(1, 0, 0, &[1]), "10000"),
+            (numeric(2, 0x4000, 1, &[12, 0, 7]), "-1200000007.0"),
+            (numeric(0, 0, 0, &[]), "0"),
+            (numeric(0, 0xC000, 0, &[]), "NaN"),
+            (numeric(0, 0xF000, 0, &[]), "-Infinity"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(text(&Type::NUMERIC, &raw), expected);
+        }
+    }
+
+    #[test]
+    fn bc_dates_and_infinity() {
+        let date = |days: i32| text(&Type::DATE, &days.to_be_bytes());
+        assert_eq!(date(0), "2000-01-01");
+        assert_eq!(date(-730_119), "0001-01-01");
+        assert_eq!(date(-730_120), "0001-12-31 BC");
+        assert_eq!(date(i32::MAX), "infinity");
+        assert_eq!(date(i32::MIN), "-infinity");
+
+        let micros = -730_120 * MICROS_PER_DAY + 3_600_000_001;
+        assert_eq!(
+            text(&Type::TIMESTAMP, &micros.to_be_bytes()),
+            "0001-12-31 01:00:00.000001 BC"
+        );
+        assert_eq!(
+            text(&Type::TIMESTAMPTZ, &0i64.to_be_bytes()),
+            "2000-01-01 00:00:00+00"
+        );
+        assert_eq!(
+            text(&Type::TIMESTAMPTZ, &i64::MIN.to_be_bytes()),
+            "-infinity"
+        );
+    }
+
+    #[test]
+    fn nested_arrays_with_nulls() {
+        // INT4[2][2] of 1, 2, 3, NULL
+        let mut raw = BytesMut::new();
+        for v in [2, 1, Type::INT4.oid() as i32, 2, 1, 2, 1] {
+            raw.put_i32(v);
+        }
+        for v in [1, 2, 3] {
+            raw.put_i32(4);
+            raw.put_i32(v);
+        }
+        raw.put_i32(-1);
+        assert_eq!(text(&Type::INT4_ARRAY, &raw), "{{1,2},{3,NULL}}");
+
+        let values = vec![Some("a b"), Some(""), Some("NULL"), None, Some("x\"y")];
+        let raw = binary(&Type::TEXT_ARRAY, &values);
+        assert_eq!(
+            text(&Type::TEXT_ARRAY, &raw),
+            r#"{"a b","","NULL",NULL,"x\"y"}"#
+        );
+
+        let empty: Vec<i32> = vec![];
+        assert_eq!(
+            text(&Type::INT4_ARRAY, &binary(&Type::INT4_ARRAY, &empty)),
+            "{}"
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let range = |r: PgRange<i32>| text(&Type::INT4_RANGE, &binary(&Type::INT4_RANGE, &r));
+        assert_eq!(range((1..10).into()), "[1,10)");
+        assert_eq!(range((1..=10).into()), "[1,10]");
+        assert_eq!(range((..5).into()), "(,5)");
+        assert_eq!(range((5..).into()), "[5,)");
+        assert_eq!(range(PgRange::empty()), "empty");
+    }
+}
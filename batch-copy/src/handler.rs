@@ -1,17 +1,34 @@
 use std::fmt::Debug;
+use std::path::Path;
+use std::sync::Arc;
 
 use bb8_postgres::PostgresConnectionManager;
 use builder_pattern::Builder;
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, BufReader};
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Duration;
 
+use tokio_postgres::config::TargetSessionAttrs;
 use tokio_postgres::NoTls;
 
 use crate::actor::{run_batch_insert_actor, BatchCopyActor, BatchCopyMessage};
+use crate::binary::encode_row;
 use crate::errors::BatchCopyDatabaseError;
+use crate::failover::Failover;
+use crate::file::CopyFileReader;
+use crate::format::{supports_text, CopyFormat};
+use crate::partition::{PartitionInterval, Partitioner};
+use crate::postgres::PostgresSink;
+use crate::route::Routes;
+use crate::schema::{create_table, schema_diff, validate};
+use crate::sequence::{SequenceTable, Sequences};
+use crate::sink::BatchSink;
+use crate::stats::{CopyStats, Counters};
+use crate::write_mode::{WriteMode, Writer};
 use crate::BatchCopyRow;
 
-type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+pub(crate) type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
 
 #[derive(Clone, Debug)]
 pub struct Copier<T>
@@ -19,10 +36,16 @@ where
     T: BatchCopyRow + Send,
 {
     sender: mpsc::Sender<BatchCopyMessage<T>>,
+    pub(crate) stats: Arc<Counters>,
+    /// the actor keeps high-water marks for `send_sequenced`
+    sequenced: bool,
+    rows_per_batch: usize,
 }
 
-#[derive(Builder)]
+#[derive(Builder, Clone)]
 pub struct Configuration {
+    /// list several hosts, e.g. `postgresql://user:pw@db1,db2/app`, to follow the
+    /// primary through a failover; only a server that accepts writes is used
     pub database_url: String,
 
     /// the actor's internal buffer
@@ -48,6 +71,75 @@ pub struct Configuration {
     /// duration to wait for a connection, seconds
     #[default(2)]
     pub pool_connect_timeout_sec: u64,
+
+    /// run the row's generated DDL (`CREATE TABLE IF NOT EXISTS`) before validating
+    #[default(false)]
+    pub create_table_if_missing: bool,
+
+    /// apply the additive part of `schema::schema_diff` (missing columns or table) before validating
+    #[default(false)]
+    pub add_missing_columns: bool,
+
+    /// create daily or monthly partitions for each flush, see `#[batch_copy(partition_by)]`
+    #[default(None)]
+    pub partition_interval: Option<PartitionInterval>,
+
+    /// plain COPY, an upsert or replace through a staging table, or a snapshot
+    #[default(WriteMode::Copy)]
+    pub write_mode: WriteMode,
+
+    /// bookkeeping table for the producer high-water marks of `Copier::send_sequenced`
+    #[default(None)]
+    pub sequence_table: Option<String>,
+
+    /// COPY in this format rather than the row's `COPY_FORMAT`; text and CSV are
+    /// slower than binary but readable in the server log and by other tools
+    #[default(None)]
+    pub copy_format: Option<CopyFormat>,
+
+    /// retry a batch this many times after the server turned out to be a standby
+    /// or dropped the connection, reconnecting to the primary each time
+    #[default(3)]
+    pub failover_retries: u32,
+
+    /// wait between failover retries, ms
+    #[default(1000)]
+    pub failover_backoff_ms: u64,
+}
+
+/// Everything needed to build the pool, kept by the actor to rebuild it after a failover
+#[derive(Clone)]
+pub(crate) struct PoolSettings {
+    config: tokio_postgres::Config,
+    max_size: u32,
+    max_lifetime: Duration,
+    connect_timeout: Duration,
+}
+
+impl PoolSettings {
+    pub(crate) fn new(cfg: &Configuration) -> Result<Self, BatchCopyDatabaseError> {
+        let mut config: tokio_postgres::Config = cfg.database_url.parse()?;
+        // skip standbys, whichever of the hosts is the primary
+        config.target_session_attrs(TargetSessionAttrs::ReadWrite);
+        Ok(Self {
+            config,
+            max_size: cfg.pool_max_size,
+            max_lifetime: Duration::from_secs(cfg.pool_max_lifetime_sec),
+            connect_timeout: Duration::from_secs(cfg.pool_connect_timeout_sec),
+        })
+    }
+
+    pub(crate) async fn build(&self) -> Result<Pool, BatchCopyDatabaseError> {
+        let mgr = PostgresConnectionManager::new(self.config.clone(), NoTls);
+
+        Ok(Pool::builder()
+            .max_size(self.max_size)
+            .max_lifetime(Some(self.max_lifetime))
+            .test_on_check_out(true)
+            .connection_timeout(self.connect_timeout)
+            .build(mgr)
+            .await?)
+    }
 }
 
 impl<T> Copier<T>
@@ -55,39 +147,126 @@ where
     T: BatchCopyRow + Send + Sync + Clone + Debug + 'static,
 {
     pub async fn new(cfg: Configuration) -> Result<Self, BatchCopyDatabaseError> {
-        let mgr = PostgresConnectionManager::new_from_stringlike(
-            cfg.database_url,
-            tokio_postgres::NoTls,
-        )?;
-
-        let pool = Pool::builder()
-            .max_size(cfg.pool_max_size)
-            .max_lifetime(Some(Duration::from_secs(cfg.pool_max_lifetime_sec)))
-            .test_on_check_out(true)
-            .connection_timeout(Duration::from_secs(cfg.pool_connect_timeout_sec))
-            .build(mgr)
-            .await?;
+        let partitioner = match cfg.partition_interval {
+            Some(interval) => match Partitioner::new::<T>(interval) {
+                Some(p) => Some(p),
+                None => {
+                    return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                        "partition_interval requires a row partitioned by range on a date or timestamp column",
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        if T::DYNAMIC_TABLE && (cfg.write_mode != WriteMode::Copy || partitioner.is_some()) {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "rows with a table_fn only support WriteMode::Copy without partitions",
+            ));
+        }
+        let routes = T::DYNAMIC_TABLE.then(|| Routes::new::<T>(cfg.create_table_if_missing));
+
+        let format = cfg.copy_format.unwrap_or(T::COPY_FORMAT);
+        if format != CopyFormat::Binary && !T::TYPES.iter().all(supports_text) {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "the row has a column type without a text encoding, use CopyFormat::Binary",
+            ));
+        }
+
+        let mut writer = Writer::new::<T>(cfg.write_mode)?;
+
+        let pool_settings = PoolSettings::new(&cfg)?;
+        let pool = pool_settings.build().await?;
 
         // Check connection and bail in case of fatal errors
-        match pool.get().await {
-            Ok(conn) => match conn.simple_query(T::CHECK_STATEMENT).await.map(|_| ()) {
-                Err(e) => {
-                    return Err(BatchCopyDatabaseError::SchemaCheckFailed {
-                        source: e,
-                        ddl: T::DDL_STATEMENT.to_string(),
-                    })
+        let (sequence_table, sequences) = match pool.get().await {
+            Ok(mut conn) => {
+                if cfg.create_table_if_missing {
+                    create_table::<T, _>(&*conn).await?;
                 }
-                _ => true,
-            },
+                if cfg.add_missing_columns {
+                    schema_diff::<T, _>(&*conn)
+                        .await?
+                        .apply_additive(&mut *conn)
+                        .await?;
+                }
+                validate::<T, _>(&*conn).await?;
+                writer.prepare(&conn).await?;
+                match cfg.sequence_table.clone() {
+                    Some(table) => {
+                        let (table, sequences) =
+                            SequenceTable::load(&conn, table, T::TABLE).await?;
+                        (Some(table), Some(sequences))
+                    }
+                    None => (None, None),
+                }
+            }
             Err(_) => return Err(BatchCopyDatabaseError::BadConnection),
         };
 
-        // Construct the channel pair and spawn the actor
-        let (tx, rx) = mpsc::channel::<BatchCopyMessage<T>>(cfg.max_channel_capacity);
-        let actor = BatchCopyActor::<T>::new(rx, pool, cfg.max_rows_per_batch);
-        tokio::spawn(run_batch_insert_actor(actor, cfg.flush_timer_ms));
+        let sink = PostgresSink {
+            pool,
+            partitioner,
+            writer,
+            sequences: sequence_table,
+            routes,
+            format,
+            failover: Failover::new(pool_settings, &cfg),
+        };
+        Ok(Self::spawn(
+            sink,
+            sequences,
+            cfg.max_rows_per_batch,
+            cfg.max_channel_capacity,
+            cfg.flush_timer_ms,
+        ))
+    }
+
+    /// A copier writing its batches to `sink` instead of Postgres.
+    ///
+    /// Rows are buffered, batched and flushed on the timer as with `Copier::new`;
+    /// `send_sequenced` is rejected. Must be called within a Tokio runtime.
+    pub fn with_sink<S>(
+        sink: S,
+        max_rows_per_batch: usize,
+        max_channel_capacity: usize,
+        flush_timer_ms: u64,
+    ) -> Self
+    where
+        S: BatchSink<T>,
+    {
+        Self::spawn(
+            sink,
+            None,
+            max_rows_per_batch,
+            max_channel_capacity,
+            flush_timer_ms,
+        )
+    }
+
+    /// Construct the channel pair and spawn the actor
+    pub(crate) fn spawn<S>(
+        sink: S,
+        sequences: Option<Sequences>,
+        max_rows_per_batch: usize,
+        max_channel_capacity: usize,
+        flush_timer_ms: u64,
+    ) -> Self
+    where
+        S: BatchSink<T>,
+    {
+        let (tx, rx) = mpsc::channel::<BatchCopyMessage<T>>(max_channel_capacity);
+        let stats = Arc::new(Counters::default());
+        let sequenced = sequences.is_some();
+        let actor = BatchCopyActor::new(rx, sink, max_rows_per_batch, sequences, stats.clone());
+        tokio::spawn(run_batch_insert_actor(actor, flush_timer_ms));
 
-        Ok(Self { sender: tx })
+        Self {
+            sender: tx,
+            stats,
+            sequenced,
+            rows_per_batch: max_rows_per_batch,
+        }
     }
 
     pub async fn send(&self, row: T) {
@@ -100,17 +279,203 @@ where
         rx.await.expect("actor was killed");
     }
 
+    /// Send a row at most once per producer sequence number.
+    ///
+    /// `seq` must increase with every row from `producer`. Rows at or below the
+    /// producer's high-water mark are skipped, and the mark is committed with the
+    /// rows, so replaying a source after a restart does not duplicate them.
+    /// Returns whether the row was accepted, or an error without
+    /// `Configuration::sequence_table`.
+    pub async fn send_sequenced(
+        &self,
+        producer: &str,
+        seq: i64,
+        row: T,
+    ) -> Result<bool, BatchCopyDatabaseError> {
+        if !self.sequenced {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "send_sequenced requires Configuration::sequence_table",
+            ));
+        }
+        let (tx, rx) = oneshot::channel();
+        let imsg = BatchCopyMessage::<T>::InsertSequenced(row, producer.to_string(), seq, tx);
+        self.sender
+            .send(imsg)
+            .await
+            .expect("sending a message should not fail");
+        Ok(rx.await.expect("actor was killed") > 0)
+    }
+
+    /// Send a borrowed row without cloning it.
+    ///
+    /// The row is encoded into the binary COPY format on the caller's task and only
+    /// the encoded bytes are sent to the actor, so `R` may borrow data that does not
+    /// outlive this call, e.g. `Event<'a>` sent through a `Copier<Event<'static>>`.
+    /// `R` must have the same columns as `T`, which is checked when the call is
+    /// compiled. Rows that fail to encode are logged and discarded.
+    ///
+    /// Encoded rows are copied after the rows sent by value in the same batch, so
+    /// interleaving `send` and `send_ref` does not keep the rows in order.
+    pub async fn send_ref<R>(&self, row: &R)
+    where
+        R: BatchCopyRow + ?Sized,
+    {
+        const {
+            assert!(
+                same_columns::<R, T>(),
+                "send_ref requires a row with the same columns as the copier"
+            )
+        };
+        let mut buf = BytesMut::new();
+        if let Err(e) = encode_row(row, &mut buf) {
+            log::error!("Error encoding row, discarded:\n\t{e}");
+            self.stats.discarded(1);
+            return;
+        }
+
+        let table = match row.table() {
+            table if T::DYNAMIC_TABLE && table != T::TABLE => Some(table.into_owned()),
+            _ => None,
+        };
+        self.send_tuples(buf.freeze(), 1, table).await;
+    }
+
+    /// Send `n` encoded tuples in one message, returning false if they filled a
+    /// batch that then failed
+    pub(crate) async fn send_tuples(&self, tuples: Bytes, n: usize, table: Option<String>) -> bool {
+        let (tx, rx) = oneshot::channel();
+        let imsg = BatchCopyMessage::<T>::InsertEncoded(tuples, n, table, tx);
+        self.sender
+            .send(imsg)
+            .await
+            .expect("sending a message should not fail");
+        rx.await.expect("actor was killed") > 0
+    }
+
+    /// Stream the tuples of a PGCOPY binary file, such as one written by
+    /// `CopyFileWriter`, through the actor a batch at a time, then flush.
+    ///
+    /// The header is checked and every tuple must have as many fields as `T` has
+    /// columns; tuples before an invalid one have already been sent. Returns the
+    /// number of tuples sent, or `FailedBatches` if any batch of them failed.
+    pub async fn load_file<R>(&self, reader: R) -> Result<u64, BatchCopyDatabaseError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut file = CopyFileReader::new(BufReader::new(reader), T::TYPES.len()).await?;
+        let mut tuples = 0;
+        let mut failed = 0;
+        let mut buf = BytesMut::new();
+        let mut buffered = 0;
+        while let Some(tuple) = file.next_tuple().await? {
+            buf.extend_from_slice(&tuple);
+            buffered += 1;
+            tuples += 1;
+            if buffered == self.rows_per_batch {
+                if !self.send_tuples(buf.split().freeze(), buffered, None).await {
+                    failed += 1;
+                }
+                buffered = 0;
+            }
+        }
+        if buffered > 0 && !self.send_tuples(buf.freeze(), buffered, None).await {
+            failed += 1;
+        }
+        if !self.flushed().await {
+            failed += 1;
+        }
+        match failed {
+            0 => Ok(tuples),
+            n => Err(BatchCopyDatabaseError::FailedBatches(n)),
+        }
+    }
+
+    /// `load_file` each file of a directory, in name order
+    pub async fn load_dir(&self, dir: impl AsRef<Path>) -> Result<u64, BatchCopyDatabaseError> {
+        let mut paths = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+
+        let mut tuples = 0;
+        for path in paths {
+            let n = self.load_file(tokio::fs::File::open(&path).await?).await?;
+            log::info!("loaded {n} rows from {}", path.display());
+            tuples += n;
+        }
+        Ok(tuples)
+    }
+
+    /// Rows and batches the actor has copied or lost so far
+    pub fn stats(&self) -> CopyStats {
+        self.stats.snapshot()
+    }
+
     pub fn ddl(&self) -> &'static str {
         T::DDL_STATEMENT
     }
 
+    /// Flush, then atomically replace the table with everything copied since the
+    /// last swap. Only for `WriteMode::Snapshot`.
+    ///
+    /// Fails without swapping if any batch failed since the last swap.
+    pub async fn swap(&self) -> Result<(), BatchCopyDatabaseError> {
+        let (tx, rx) = oneshot::channel();
+        let imsg = BatchCopyMessage::Swap(tx);
+        self.sender
+            .send(imsg)
+            .await
+            .expect("sending a message should not fail");
+        rx.await.expect("actor was killed")
+    }
+
     pub async fn flush(&self) {
+        self.flushed().await;
+    }
+
+    /// Flush, returning false if the buffered rows failed to copy
+    pub(crate) async fn flushed(&self) -> bool {
         let (tx, rx) = oneshot::channel();
         let imsg = BatchCopyMessage::Flush(tx);
         self.sender
             .send(imsg)
             .await
             .expect("sending a message should not fail");
-        rx.await.expect("actor was killed");
+        rx.await.expect("actor was killed") > 0
+    }
+}
+
+/// Whether rows of `R` and `T` go to the same columns with the same types, from
+/// their COPY statements and column types since `Type` cannot be compared in a
+/// constant
+const fn same_columns<R: BatchCopyRow + ?Sized, T: BatchCopyRow>() -> bool {
+    const fn same(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+    if R::COLUMNS.len() != T::COLUMNS.len() || !same(R::COPY_STATEMENT, T::COPY_STATEMENT) {
+        return false;
+    }
+    let mut i = 0;
+    while i < R::COLUMNS.len() {
+        if !same(R::COLUMNS[i].sql_type, T::COLUMNS[i].sql_type) {
+            return false;
+        }
+        i += 1;
     }
+    true
 }
@@ -0,0 +1,58 @@
+use std::error::Error;
+use std::fmt::Debug;
+
+use bytes::{BufMut, BytesMut};
+use serde::Serialize;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+
+/// Wraps any `Serialize` value so it can be written to a JSON or JSONB column.
+///
+/// Fields annotated with `#[batch_copy(json)]` or `#[batch_copy(jsonb)]` are
+/// encoded through this wrapper; the value is serialized straight into the
+/// COPY buffer without an intermediate `serde_json::Value`.
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct Json<T>(pub T);
+
+impl<T> Json<T> {
+    /// View a borrowed value as `Json`, without copying it.
+    pub fn from_ref(value: &T) -> &Self {
+        // Safety: Json is repr(transparent) over T
+        unsafe { &*(value as *const T as *const Self) }
+    }
+}
+
+impl<T> ToSql for Json<T>
+where
+    T: Serialize + Debug,
+{
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        // the JSONB binary format is a version byte followed by the JSON text
+        if *ty == Type::JSONB {
+            out.put_u8(1);
+        }
+        serde_json::to_writer(out.writer(), &self.0)?;
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(*ty, Type::JSON | Type::JSONB)
+    }
+
+    to_sql_checked!();
+}
+
+/// `None` becomes SQL `NULL` rather than the JSON literal `null`
+pub fn json_option<T>(value: &Option<T>) -> &(dyn ToSql + Sync)
+where
+    T: Serialize + Debug + Sync,
+{
+    match value {
+        Some(v) => Json::from_ref(v),
+        None => &None::<Json<()>>,
+    }
+}
@@ -1,14 +1,60 @@
-#![doc = include_str!("../README.md")]
+#![doc = include_str!("../../README.md")]
 
+use std::borrow::Cow;
+use std::error::Error;
+
+use bytes::BytesMut;
 use tokio_postgres::types::{ToSql, Type};
 
-/// The batch copy actor recieves messages, buffers them, and periodically flushes to postgresql using binary COPY.
+/// The batch copy actor recieves messages, buffers them, and periodically flushes them to its sink, Postgres COPY by default.
 pub mod actor;
+/// Copy Arrow record batches without row structs
+#[cfg(feature = "arrow")]
+pub mod arrow;
+mod binary;
+mod columns;
 /// Potential error states
 pub mod errors;
+mod failover;
+/// PGCOPY binary files written without a database
+pub mod file;
+/// COPY in the text and CSV formats as well as binary
+pub mod format;
 /// The copier takes BatchCopyRow values and sends them to the actor on a channel.
 /// Copiers are inexpensive to clone and can be used on multiple threads/tasks.
 pub mod handler;
+/// Serde-backed JSON and JSONB columns
+#[cfg(feature = "serde")]
+pub mod json;
+/// In-memory copiers for tests without a database
+#[cfg(feature = "testing")]
+pub mod mock;
+/// One copier for rows bound for several tables
+pub mod multi;
+/// NUMERIC columns from `bigdecimal::BigDecimal`
+#[cfg(feature = "bigdecimal")]
+pub mod numeric;
+/// Load Parquet files through an `ArrowCopier`
+#[cfg(feature = "parquet")]
+pub mod parquet;
+/// Create range partitions ahead of each flush
+pub mod partition;
+mod postgres;
+/// Range columns such as INT8RANGE and TSTZRANGE
+pub mod range;
+mod route;
+/// Validate and migrate the live table against a row's columns
+pub mod schema;
+mod sequence;
+/// One copier per database, rows routed by a shard key
+pub mod shard;
+/// Where batches are written, Postgres COPY unless given another sink
+pub mod sink;
+mod stats;
+/// Copy rows of a table whose columns are read from the database
+pub mod table;
+/// Plain COPY or upserts through a staging table
+pub mod write_mode;
 
 // Public API
 
@@ -17,18 +63,81 @@ pub mod handler;
 pub use handler::{Configuration, Copier};
 
 pub use batch_copy_derive::BatchCopy;
+pub use columns::{Column, TableSchema};
+pub use file::CopyFileWriter;
+pub use format::CopyFormat;
+pub use multi::MultiCopier;
+pub use partition::PartitionInterval;
+pub use range::PgRange;
+pub use shard::ShardedCopier;
+pub use sink::{Batch, BatchSink};
+pub use stats::CopyStats;
+pub use table::TableCopier;
+pub use write_mode::WriteMode;
+
+#[cfg(feature = "arrow")]
+pub use arrow::ArrowCopier;
+#[cfg(feature = "serde")]
+pub use json::Json;
+#[cfg(feature = "testing")]
+pub use mock::Mock;
+#[cfg(feature = "bigdecimal")]
+pub use numeric::Numeric;
+#[cfg(feature = "parquet")]
+pub use parquet::ParquetLoader;
 
 #[doc(hidden)]
 pub mod __private {
+    pub use bytes::BytesMut;
+    pub use const_format::concatcp;
     pub use tokio_postgres::types::{ToSql, Type};
+
+    pub use crate::binary::encode_row;
+    pub use crate::columns::{has_list_item, strip_list_sep, ColumnGroup, Concat, ConcatParts};
+
+    #[cfg(feature = "serde")]
+    pub use crate::json::{json_option, Json};
+    #[cfg(feature = "bigdecimal")]
+    pub use crate::numeric::{numeric_option, Numeric};
 }
 
 /// translate your struct to postgres details
 pub trait BatchCopyRow {
+    /// the target table, as given to `#[batch_copy(table = "...")]`
+    const TABLE: &'static str;
+    /// the key column of a table partitioned by range on a single date or timestamp
+    const PARTITION_COLUMN: Option<&'static str> = None;
+    /// the column whose value picks a database, see `#[batch_copy(shard_key)]`
+    const SHARD_COLUMN: Option<&'static str> = None;
     const TYPES: &'static [Type];
+    /// name, DDL type and nullability of each column, in the same order as `TYPES`
+    const COLUMNS: &'static [Column];
     const COPY_STATEMENT: &'static str;
+    /// the format rows are sent in, see `#[batch_copy(format = "csv")]`
+    const COPY_FORMAT: CopyFormat = CopyFormat::Binary;
     const CHECK_STATEMENT: &'static str;
     const DDL_STATEMENT: &'static str;
+    /// whether `table` may return tables other than `TABLE`, see `#[batch_copy(table_fn)]`
+    const DYNAMIC_TABLE: bool = false;
 
     fn fill_copy_refs<'a>(&'a self, out: &mut Vec<&'a (dyn ToSql + Sync)>);
+
+    /// the table this row is copied to; a dynamic table has the same columns as `TABLE`
+    fn table(&self) -> Cow<'_, str> {
+        Cow::Borrowed(Self::TABLE)
+    }
+}
+
+/// An enum whose variants each hold a row for a different table.
+///
+/// `#[derive(BatchCopy)]` on an enum of single-field tuple variants implements it,
+/// for use with `MultiCopier`.
+pub trait BatchCopyTables {
+    /// the schema of each variant's row, in declaration order
+    const TABLES: &'static [TableSchema];
+
+    /// the position of this row's table in `TABLES`
+    fn table_index(&self) -> usize;
+    /// append this row to `buf` as a binary COPY tuple
+    fn encode(&self, buf: &mut BytesMut) -> Result<(), Box<dyn Error + Sync + Send>>;
 }
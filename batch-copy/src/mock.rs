@@ -0,0 +1,144 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::handler::{Configuration, Copier};
+use crate::sink::{Batch, BatchSink};
+use crate::BatchCopyRow;
+
+/// The batches a mock copier has committed, for tests to assert on.
+///
+/// Clones share the same record.
+pub struct Mock<T> {
+    state: Arc<Mutex<MockState<T>>>,
+}
+
+struct MockState<T> {
+    batches: Vec<Vec<T>>,
+    /// tuples sent already encoded, by `send_ref` or `load_file`
+    encoded_rows: usize,
+    /// writes still to fail
+    failures: u32,
+}
+
+impl<T> Clone for Mock<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Clone> Mock<T> {
+    fn state(&self) -> MutexGuard<'_, MockState<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Every committed row sent by value, in order
+    pub fn rows(&self) -> Vec<T> {
+        self.state().batches.concat()
+    }
+
+    /// The rows of each committed batch, in order
+    pub fn batches(&self) -> Vec<Vec<T>> {
+        self.state().batches.clone()
+    }
+
+    /// Committed rows that were sent already encoded, which cannot be read back as `T`
+    pub fn encoded_rows(&self) -> usize {
+        self.state().encoded_rows
+    }
+
+    /// Fail the next `n` batches, which are discarded as after a database error
+    pub fn fail_next(&self, n: u32) {
+        self.state().failures = n;
+    }
+
+    /// Forget the committed batches
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.batches.clear();
+        state.encoded_rows = 0;
+    }
+}
+
+/// Commits batches to a shared `Mock`
+struct MockSink<T> {
+    mock: Mock<T>,
+}
+
+impl<T> BatchSink<T> for MockSink<T>
+where
+    T: BatchCopyRow + Clone + Send + Sync + 'static,
+{
+    type Transaction = (Vec<T>, usize);
+
+    async fn begin(
+        &mut self,
+        _batch: &mut Batch<T>,
+    ) -> Result<(Vec<T>, usize), Box<dyn Error + Sync + Send>> {
+        Ok((vec![], 0))
+    }
+
+    async fn write(
+        &mut self,
+        transaction: &mut (Vec<T>, usize),
+        batch: &Batch<T>,
+    ) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        {
+            let mut state = self.mock.state();
+            if state.failures > 0 {
+                state.failures -= 1;
+                return Err("failure injected by Mock::fail_next".into());
+            }
+        }
+        transaction.0.extend_from_slice(batch.rows());
+        transaction.1 = batch.len() - batch.rows().len();
+        Ok(batch.len() as u64)
+    }
+
+    async fn commit(
+        &mut self,
+        (rows, encoded_rows): (Vec<T>, usize),
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let mut state = self.mock.state();
+        state.batches.push(rows);
+        state.encoded_rows += encoded_rows;
+        Ok(())
+    }
+
+    async fn abort(&mut self, _transaction: (Vec<T>, usize)) {}
+}
+
+impl<T> Copier<T>
+where
+    T: BatchCopyRow + Send + Sync + Clone + Debug + 'static,
+{
+    /// A copier that commits its batches to memory instead of a database, with the
+    /// `Mock` recording them. Batches are sized and flushed as with a default
+    /// `Configuration`. Must be called within a Tokio runtime.
+    pub fn mock() -> (Self, Mock<T>) {
+        // the URL is required but never used
+        Self::mock_with(&Configuration::new().database_url(String::new()).build())
+    }
+
+    /// `mock` with the batch size, channel capacity and flush timer of `cfg`. Its
+    /// database URL and other settings are ignored.
+    pub fn mock_with(cfg: &Configuration) -> (Self, Mock<T>) {
+        let mock = Mock {
+            state: Arc::new(Mutex::new(MockState {
+                batches: vec![],
+                encoded_rows: 0,
+                failures: 0,
+            })),
+        };
+        let sink = MockSink { mock: mock.clone() };
+        let copier = Self::with_sink(
+            sink,
+            cfg.max_rows_per_batch,
+            cfg.max_channel_capacity,
+            cfg.flush_timer_ms,
+        );
+        (copier, mock)
+    }
+}
@@ -0,0 +1,207 @@
+use std::error::Error;
+use std::marker::PhantomData;
+
+use bytes::{Bytes, BytesMut};
+use futures_util::{pin_mut, SinkExt};
+use tokio_postgres::Client;
+
+use crate::binary::{HEADER, TRAILER};
+use crate::columns::TableSchema;
+use crate::errors::BatchCopyDatabaseError;
+use crate::failover::{checkout, Failover};
+use crate::format::{supports_text, CopyFormat};
+use crate::handler::{Configuration, Copier, Pool, PoolSettings};
+use crate::postgres::{commit, PostgresTransaction};
+use crate::schema::{create_table_schema, validate_schema};
+use crate::sink::{Batch, BatchSink};
+use crate::stats::CopyStats;
+use crate::table::Encoded;
+use crate::write_mode::WriteMode;
+use crate::BatchCopyTables;
+
+/// A copier for an enum of rows bound for different tables.
+///
+/// One actor and one pool serve every table. Rows are encoded on the caller's
+/// task and buffered per table, and a flush copies all pending tables in one
+/// transaction, so rows sent before the same flush commit together or not at all.
+#[derive(Debug)]
+pub struct MultiCopier<E> {
+    copier: Copier<Encoded>,
+    rows: PhantomData<fn(E)>,
+}
+
+impl<E> Clone for MultiCopier<E> {
+    fn clone(&self) -> Self {
+        Self {
+            copier: self.copier.clone(),
+            rows: PhantomData,
+        }
+    }
+}
+
+impl<E> MultiCopier<E>
+where
+    E: BatchCopyTables,
+{
+    /// Validates every table, creating missing ones with `create_table_if_missing`.
+    ///
+    /// Only `WriteMode::Copy` is supported, without partitions, sequences or
+    /// added columns.
+    pub async fn new(cfg: Configuration) -> Result<Self, BatchCopyDatabaseError> {
+        if cfg.write_mode != WriteMode::Copy
+            || cfg.partition_interval.is_some()
+            || cfg.sequence_table.is_some()
+            || cfg.add_missing_columns
+        {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "MultiCopier only supports WriteMode::Copy without partitions, sequences or added columns",
+            ));
+        }
+
+        let text_ok = |schema: &TableSchema| {
+            cfg.copy_format.unwrap_or(schema.copy_format) == CopyFormat::Binary
+                || schema.types.iter().all(supports_text)
+        };
+        if !E::TABLES.iter().all(text_ok) {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "a table has a column type without a text encoding, use CopyFormat::Binary",
+            ));
+        }
+
+        let pool_settings = PoolSettings::new(&cfg)?;
+        let pool = pool_settings.build().await?;
+
+        // Check connection and bail in case of fatal errors
+        match pool.get().await {
+            Ok(conn) => {
+                for schema in E::TABLES {
+                    if cfg.create_table_if_missing {
+                        create_table_schema(&*conn, schema).await?;
+                    }
+                    validate_schema(&*conn, schema, schema.check_statement).await?;
+                }
+            }
+            Err(_) => return Err(BatchCopyDatabaseError::BadConnection),
+        };
+
+        let sink = MultiSink {
+            pool,
+            tables: E::TABLES,
+            format: cfg.copy_format,
+            failover: Failover::new(pool_settings, &cfg),
+        };
+        Ok(Self {
+            copier: Copier::spawn(
+                sink,
+                None,
+                cfg.max_rows_per_batch,
+                cfg.max_channel_capacity,
+                cfg.flush_timer_ms,
+            ),
+            rows: PhantomData,
+        })
+    }
+
+    /// Encode the row on the caller's task and buffer it for its variant.
+    /// Rows that fail to encode are logged and discarded.
+    pub async fn send(&self, row: E) {
+        let mut buf = BytesMut::new();
+        if let Err(e) = row.encode(&mut buf) {
+            log::error!("Error encoding row, discarded:\n\t{e}");
+            self.copier.stats.discarded(1);
+            return;
+        }
+
+        // keyed by variant rather than table, since two variants may copy
+        // different columns into the same table
+        let variant = row.table_index().to_string();
+        self.copier.send_tuples(buf.freeze(), 1, Some(variant)).await;
+    }
+
+    pub async fn flush(&self) {
+        self.copier.flush().await;
+    }
+
+    /// Rows and batches the actor has copied or lost so far, across all tables
+    pub fn stats(&self) -> CopyStats {
+        self.copier.stats()
+    }
+}
+
+/// The sink behind `MultiCopier`: COPY each variant's tuples, one transaction per batch
+struct MultiSink {
+    pool: Pool,
+    tables: &'static [TableSchema],
+    /// overrides each table's `copy_format`
+    format: Option<CopyFormat>,
+    failover: Failover,
+}
+
+impl BatchSink<Encoded> for MultiSink {
+    type Transaction = PostgresTransaction;
+
+    async fn begin(
+        &mut self,
+        _batch: &mut Batch<Encoded>,
+    ) -> Result<PostgresTransaction, Box<dyn Error + Sync + Send>> {
+        let connection = checkout(&self.pool).await?;
+        self.failover.check(&connection).await?;
+        Ok(PostgresTransaction::new(connection))
+    }
+
+    async fn write(
+        &mut self,
+        transaction: &mut PostgresTransaction,
+        batch: &Batch<Encoded>,
+    ) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        let transaction = transaction.transaction().await?;
+        let mut nrows = 0;
+        // in declaration order, so tables referenced by foreign keys load first
+        for (variant, schema) in self.tables.iter().enumerate() {
+            if let Some((tuples, _)) = batch.tables.get(&variant.to_string()) {
+                nrows +=
+                    copy_table(transaction.client(), schema, tuples.clone(), self.format).await?;
+            }
+        }
+        commit(transaction).await?;
+        Ok(nrows)
+    }
+
+    async fn commit(
+        &mut self,
+        _transaction: PostgresTransaction,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        Ok(())
+    }
+
+    // dropping the transaction in `write` rolled it back
+    async fn abort(&mut self, _transaction: PostgresTransaction) {}
+
+    async fn retry(&mut self, error: &(dyn Error + Sync + Send + 'static), attempt: u32) -> bool {
+        self.failover.retry(&mut self.pool, error, attempt).await
+    }
+}
+
+/// COPY one table's encoded tuples
+async fn copy_table(
+    client: &Client,
+    schema: &TableSchema,
+    tuples: Bytes,
+    format: Option<CopyFormat>,
+) -> Result<u64, Box<dyn Error + Sync + Send>> {
+    let format = format.unwrap_or(schema.copy_format);
+    let sink = client
+        .copy_in(&format.copy_statement(schema.copy_statement))
+        .await?;
+    pin_mut!(sink);
+    if format == CopyFormat::Binary {
+        sink.send(Bytes::from_static(HEADER)).await?;
+        sink.send(tuples).await?;
+        sink.send(Bytes::from_static(TRAILER)).await?;
+    } else {
+        let mut text = BytesMut::with_capacity(tuples.len() * 2);
+        format.write_tuples(schema.types, &tuples, &mut text)?;
+        sink.send(text.freeze()).await?;
+    }
+    Ok(sink.finish().await?)
+}
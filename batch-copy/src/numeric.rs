@@ -0,0 +1,57 @@
+use std::error::Error;
+
+use bigdecimal::num_bigint::Sign;
+use bigdecimal::BigDecimal;
+use bytes::BytesMut;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+
+use crate::binary::put_numeric;
+
+/// Wraps a `BigDecimal` so it can be written to a NUMERIC column.
+///
+/// `tokio-postgres` has no `ToSql` impl for `BigDecimal`; fields of that type
+/// are encoded through this wrapper by `#[derive(BatchCopy)]`.
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct Numeric(pub BigDecimal);
+
+impl Numeric {
+    /// View a borrowed value as `Numeric`, without copying it.
+    pub fn from_ref(value: &BigDecimal) -> &Self {
+        // Safety: Numeric is repr(transparent) over BigDecimal
+        unsafe { &*(value as *const BigDecimal as *const Self) }
+    }
+}
+
+impl ToSql for Numeric {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let (int, exp) = self.0.as_bigint_and_exponent();
+        put_numeric(
+            int.sign() == Sign::Minus,
+            int.magnitude().to_string(),
+            exp,
+            out,
+        )?;
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+
+    to_sql_checked!();
+}
+
+static NULL: Option<Numeric> = None;
+
+/// `None` becomes SQL `NULL`
+pub fn numeric_option(value: &Option<BigDecimal>) -> &(dyn ToSql + Sync) {
+    match value {
+        Some(v) => Numeric::from_ref(v),
+        None => &NULL,
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use ::parquet::arrow::{ParquetRecordBatchStreamBuilder, ProjectionMask};
+use ::parquet::errors::ParquetError;
+use arrow_array::RecordBatch;
+use arrow_schema::{Schema, SchemaRef};
+use futures_util::TryStreamExt;
+
+use crate::arrow::{cast_column, ArrowCopier};
+use crate::errors::BatchCopyDatabaseError;
+use crate::handler::Configuration;
+use crate::stats::CopyStats;
+
+/// Rows per `RecordBatch` read from a row group
+const BATCH_SIZE: usize = 8192;
+
+/// Loads Parquet files into a table through an `ArrowCopier`, one row group at a time.
+///
+/// The copier is created with `ArrowCopier::with_casts` from the schema of the
+/// first file, after projection and renaming, so fields are converted to the
+/// types of their columns. Later files are converted to that schema.
+pub struct ParquetLoader {
+    table: String,
+    /// builds the copier once a file gives the schema
+    cfg: Configuration,
+    copier: Option<ArrowCopier>,
+    /// the Parquet columns to load, all of them if `None`
+    columns: Option<Vec<String>>,
+    /// table column for each renamed Parquet column
+    renames: HashMap<String, String>,
+    batch_size: usize,
+}
+
+impl ParquetLoader {
+    pub fn new(cfg: Configuration, table: impl Into<String>) -> Self {
+        Self {
+            table: table.into(),
+            cfg,
+            copier: None,
+            columns: None,
+            renames: HashMap::new(),
+            batch_size: BATCH_SIZE,
+        }
+    }
+
+    /// Load only these Parquet columns, by their name in the file
+    pub fn columns<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.columns = Some(columns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Copy the Parquet column `from` to the table column `to`
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.renames.insert(from.into(), to.into());
+        self
+    }
+
+    /// Rows decoded at a time, 8192 by default
+    pub fn batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows.max(1);
+        self
+    }
+
+    /// Send every row of a Parquet file to the copier, then flush. Returns the
+    /// number of rows sent, or `FailedBatches` if any batch of them failed to copy;
+    /// rows before a rejected batch have already been sent.
+    pub async fn load_file(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<u64, BatchCopyDatabaseError> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path).await?;
+        let builder = ParquetRecordBatchStreamBuilder::new(file).await?;
+
+        let source = builder.schema().clone();
+        let indices = match &self.columns {
+            None => (0..source.fields().len()).collect(),
+            Some(columns) => columns
+                .iter()
+                .map(|name| {
+                    source.index_of(name).map_err(|_| {
+                        ParquetError::General(format!("no column `{name}` in {}", path.display()))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+        let mask = ProjectionMask::roots(builder.parquet_schema(), indices.iter().copied());
+        let mut stream = builder
+            .with_projection(mask)
+            .with_batch_size(self.batch_size)
+            .build()?;
+
+        let mut rows = 0;
+        let mut failed = 0;
+        while let Some(batch) = stream.try_next().await? {
+            let batch = self.renamed(batch)?;
+            let copier = match &self.copier {
+                Some(copier) => copier,
+                None => {
+                    let cfg = self.cfg.clone();
+                    let copier = ArrowCopier::with_casts(cfg, &self.table, batch.schema()).await?;
+                    self.copier.insert(copier)
+                }
+            };
+            let batch = conform(batch, copier.schema())?;
+            failed += copier.send_checked(&batch).await?;
+            rows += batch.num_rows() as u64;
+        }
+        if let Some(copier) = &self.copier {
+            if !copier.flushed().await {
+                failed += 1;
+            }
+        }
+        match failed {
+            0 => Ok(rows),
+            n => Err(BatchCopyDatabaseError::FailedBatches(n)),
+        }
+    }
+
+    /// `load_file` each file of a directory ending in `.parquet`, in name order
+    pub async fn load_dir(&mut self, dir: impl AsRef<Path>) -> Result<u64, BatchCopyDatabaseError> {
+        let mut paths = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_file()
+                && path.extension().is_some_and(|ext| ext == "parquet")
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut rows = 0;
+        for path in paths {
+            let n = self.load_file(&path).await?;
+            log::info!("loaded {n} rows from {}", path.display());
+            rows += n;
+        }
+        Ok(rows)
+    }
+
+    /// The copier, once the first file has been read
+    pub fn copier(&self) -> Option<&ArrowCopier> {
+        self.copier.as_ref()
+    }
+
+    /// Rows and batches the actor has copied or lost so far
+    pub fn stats(&self) -> CopyStats {
+        self.copier.as_ref().map(|c| c.stats()).unwrap_or_default()
+    }
+
+    /// Give the fields of `batch` their table column names
+    fn renamed(&self, batch: RecordBatch) -> Result<RecordBatch, BatchCopyDatabaseError> {
+        if self.renames.is_empty() {
+            return Ok(batch);
+        }
+        let fields: Vec<_> = batch
+            .schema_ref()
+            .fields()
+            .iter()
+            .map(|field| match self.renames.get(field.name()) {
+                Some(name) => Arc::new(field.as_ref().clone().with_name(name)),
+                None => field.clone(),
+            })
+            .collect();
+        let schema = Arc::new(Schema::new(fields));
+        Ok(RecordBatch::try_new(schema, batch.columns().to_vec()).map_err(ParquetError::from)?)
+    }
+}
+
+/// Reorder and convert the columns of `batch` to `schema`, for files whose
+/// types differ from the first file's
+fn conform(batch: RecordBatch, schema: &SchemaRef) -> Result<RecordBatch, BatchCopyDatabaseError> {
+    if batch.schema_ref() == schema {
+        return Ok(batch);
+    }
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let array = batch.column_by_name(field.name()).ok_or_else(|| {
+                ParquetError::General(format!("the file has no column `{}`", field.name()))
+            })?;
+            if array.data_type() == field.data_type() {
+                return Ok(array.clone());
+            }
+            cast_column(array, field.data_type(), field.name())
+        })
+        .collect::<Result<Vec<_>, BatchCopyDatabaseError>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns).map_err(ParquetError::from)?)
+}
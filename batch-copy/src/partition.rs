@@ -0,0 +1,174 @@
+use std::collections::{BTreeSet, HashSet};
+
+use bytes::BytesMut;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::{IsNull, ToSql, Type};
+use tokio_postgres::Client;
+
+use crate::binary::column_values;
+use crate::BatchCopyRow;
+
+/// Days from 1970-01-01 to 2000-01-01, the epoch of postgres' binary dates
+pub(crate) const PG_EPOCH_DAYS: i64 = 10_957;
+pub(crate) const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+/// The span of each partition created ahead of a flush
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionInterval {
+    Daily,
+    Monthly,
+}
+
+/// Creates the range partitions a batch needs before it is copied.
+///
+/// The partition key is read back from each row's binary encoding, so it works
+/// the same for buffered rows and for rows encoded by `Copier::send_ref`.
+pub(crate) struct Partitioner {
+    table: &'static str,
+    column: usize,
+    ty: Type,
+    interval: PartitionInterval,
+    /// first day of each period known to be covered by a partition
+    covered: HashSet<i64>,
+}
+
+impl Partitioner {
+    /// `None` unless `T` is range partitioned on a DATE, TIMESTAMP or TIMESTAMPTZ column
+    pub(crate) fn new<T: BatchCopyRow>(interval: PartitionInterval) -> Option<Self> {
+        let name = T::PARTITION_COLUMN?;
+        let column = T::COLUMNS.iter().position(|c| c.name == name)?;
+        let ty = T::TYPES[column].clone();
+        if !matches!(ty, Type::DATE | Type::TIMESTAMP | Type::TIMESTAMPTZ) {
+            return None;
+        }
+        Some(Self {
+            table: T::TABLE,
+            column,
+            ty,
+            interval,
+            covered: HashSet::new(),
+        })
+    }
+
+    /// Create a partition for every period in the batch that has none yet
+    pub(crate) async fn ensure<T: BatchCopyRow>(
+        &mut self,
+        client: &Client,
+        rows: &[T],
+        encoded: &[u8],
+    ) {
+        let mut periods = BTreeSet::new();
+        let mut buf = BytesMut::new();
+        for row in rows {
+            let mut values: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(T::TYPES.len());
+            row.fill_copy_refs(&mut values);
+            buf.clear();
+            if let Some(Ok(IsNull::No)) = values
+                .get(self.column)
+                .map(|v| v.to_sql_checked(&self.ty, &mut buf))
+            {
+                periods.extend(self.period_of(&buf));
+            }
+        }
+        for value in column_values(encoded, self.column).into_iter().flatten() {
+            periods.extend(self.period_of(value));
+        }
+
+        for start in periods {
+            if self.covered.contains(&start) {
+                continue;
+            }
+            let (name, sql) = self.create_statement(start);
+            match client.batch_execute(&sql).await {
+                Ok(()) => {
+                    log::info!("created partition {name}");
+                    self.covered.insert(start);
+                }
+                // an existing partition already takes these values
+                Err(e) if e.code() == Some(&SqlState::INVALID_OBJECT_DEFINITION) => {
+                    self.covered.insert(start);
+                }
+                Err(e) => log::warn!("could not create partition {name}:\n\t{e}"),
+            }
+        }
+    }
+
+    /// First day of the period containing a binary DATE or TIMESTAMP(TZ) value
+    fn period_of(&self, value: &[u8]) -> Option<i64> {
+        let day = if self.ty == Type::DATE {
+            match i32::from_be_bytes(value.try_into().ok()?) {
+                i32::MIN | i32::MAX => return None,
+                days => days as i64,
+            }
+        } else {
+            match i64::from_be_bytes(value.try_into().ok()?) {
+                i64::MIN | i64::MAX => return None,
+                micros => micros.div_euclid(MICROS_PER_DAY),
+            }
+        } + PG_EPOCH_DAYS;
+
+        match self.interval {
+            PartitionInterval::Daily => Some(day),
+            PartitionInterval::Monthly => {
+                let (y, m, _) = civil_from_days(day);
+                Some(days_from_civil(y, m, 1))
+            }
+        }
+    }
+
+    fn create_statement(&self, start: i64) -> (String, String) {
+        let (y, m, d) = civil_from_days(start);
+        let (name, end) = match self.interval {
+            PartitionInterval::Daily => (format!("{}_p{y:04}{m:02}{d:02}", self.table), start + 1),
+            PartitionInterval::Monthly => {
+                let (ny, nm) = if m == 12 { (y + 1, 1) } else { (y, m + 1) };
+                (
+                    format!("{}_p{y:04}{m:02}", self.table),
+                    days_from_civil(ny, nm, 1),
+                )
+            }
+        };
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {name} PARTITION OF {} FOR VALUES FROM ({}) TO ({})",
+            self.table,
+            self.bound(start),
+            self.bound(end)
+        );
+        (name, sql)
+    }
+
+    /// A partition bound literal at midnight UTC
+    fn bound(&self, day: i64) -> String {
+        let (y, m, d) = civil_from_days(day);
+        match self.ty {
+            Type::TIMESTAMPTZ => format!("'{y:04}-{m:02}-{d:02} 00:00:00+00'"),
+            _ => format!("'{y:04}-{m:02}-{d:02}'"),
+        }
+    }
+}
+
+// Conversions between days since 1970-01-01 and the proleptic Gregorian calendar,
+// from http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
@@ -0,0 +1,209 @@
+use std::error::Error;
+
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::{pin_mut, SinkExt};
+use tokio_postgres::{Client, CopyInSink, NoTls, Transaction};
+
+use crate::binary::{encode_row, HEADER, TRAILER};
+use crate::errors::BatchCopyDatabaseError;
+use crate::failover::{checkout, Failover, FlushError};
+use crate::format::CopyFormat;
+use crate::handler::Pool;
+use crate::partition::Partitioner;
+use crate::route::{Route, Routes};
+use crate::sequence::SequenceTable;
+use crate::sink::{Batch, BatchSink};
+use crate::write_mode::Writer;
+use crate::BatchCopyRow;
+
+/// Send the COPY data to the server in chunks of about this size
+const CHUNK_SIZE: usize = 4096;
+
+/// The sink behind `Copier::new`: COPY into Postgres, one transaction per batch
+pub(crate) struct PostgresSink {
+    pub(crate) pool: Pool,
+    pub(crate) partitioner: Option<Partitioner>,
+    pub(crate) writer: Writer,
+    pub(crate) sequences: Option<SequenceTable>,
+    /// other tables chosen by the rows, for a row type with a dynamic table
+    pub(crate) routes: Option<Routes>,
+    /// rows are buffered as binary tuples and converted as they are sent
+    pub(crate) format: CopyFormat,
+    pub(crate) failover: Failover,
+}
+
+/// A connection to the primary, checked out for one batch.
+///
+/// `write` runs the batch in a `Transaction` and commits it before returning, so
+/// a flush that is dropped or panics part way rolls back before the connection
+/// can go back to the pool. `commit` only records that it did.
+pub(crate) struct PostgresTransaction {
+    connection: PooledConnection<'static, PostgresConnectionManager<NoTls>>,
+}
+
+impl PostgresTransaction {
+    pub(crate) fn new(
+        connection: PooledConnection<'static, PostgresConnectionManager<NoTls>>,
+    ) -> Self {
+        Self { connection }
+    }
+
+    pub(crate) async fn transaction(&mut self) -> Result<Transaction<'_>, tokio_postgres::Error> {
+        self.connection.transaction().await
+    }
+}
+
+/// Commit the transaction of a batch; a failure is never retried
+pub(crate) async fn commit(
+    transaction: Transaction<'_>,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    transaction.commit().await.map_err(FlushError::Commit)?;
+    Ok(())
+}
+
+impl<T> BatchSink<T> for PostgresSink
+where
+    T: BatchCopyRow + Send + Sync + 'static,
+{
+    type Transaction = PostgresTransaction;
+
+    async fn begin(
+        &mut self,
+        batch: &mut Batch<T>,
+    ) -> Result<PostgresTransaction, Box<dyn Error + Sync + Send>> {
+        let connection = checkout(&self.pool).await?;
+        self.failover.check(&connection).await?;
+
+        // Partitions are created outside the COPY transaction, so they are kept even if it fails
+        if let Some(partitioner) = &mut self.partitioner {
+            partitioner
+                .ensure(&connection, &batch.rows, &batch.encoded)
+                .await;
+        }
+
+        // Rows bound for other tables are grouped by table and copied after T::TABLE
+        if let Some(routes) = &mut self.routes {
+            routes.split(&connection, batch).await?;
+        }
+
+        Ok(PostgresTransaction::new(connection))
+    }
+
+    async fn write(
+        &mut self,
+        transaction: &mut PostgresTransaction,
+        batch: &Batch<T>,
+    ) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        let transaction = transaction.transaction().await?;
+        let client = transaction.client();
+        let copy_statement = self.writer.begin(client).await?;
+        let sink = client
+            .copy_in(&self.format.copy_statement(copy_statement))
+            .await?;
+        let copied = copy_rows(sink, &batch.rows, batch.encoded.clone(), self.format).await?;
+        let mut nrows = self.writer.finish(client).await?.unwrap_or(copied);
+        nrows += copy_routes(client, &batch.routed, self.format).await?;
+        if let Some(sequences) = &self.sequences {
+            sequences.record(client, &batch.marks).await?;
+        }
+        commit(transaction).await?;
+        Ok(nrows)
+    }
+
+    async fn commit(
+        &mut self,
+        _transaction: PostgresTransaction,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        self.writer.committed();
+        Ok(())
+    }
+
+    // dropping the transaction in `write` rolled it back
+    async fn abort(&mut self, _transaction: PostgresTransaction) {}
+
+    async fn retry(&mut self, error: &(dyn Error + Sync + Send + 'static), attempt: u32) -> bool {
+        self.failover.retry(&mut self.pool, error, attempt).await
+    }
+
+    fn flushed(&mut self) {
+        self.writer.flushed();
+    }
+
+    async fn swap(&mut self) -> Result<(), BatchCopyDatabaseError> {
+        let mut connection = self
+            .pool
+            .get()
+            .await
+            .map_err(|_| BatchCopyDatabaseError::BadConnection)?;
+        self.writer.swap(&mut connection).await?;
+        log::info!("swapped in the snapshot of {}", T::TABLE);
+        Ok(())
+    }
+}
+
+/// Stream rows through a COPY sink in `format` and complete the COPY
+async fn copy_rows<T>(
+    sink: CopyInSink<Bytes>,
+    rows: &[T],
+    encoded: Bytes,
+    format: CopyFormat,
+) -> Result<u64, Box<dyn Error + Sync + Send>>
+where
+    T: BatchCopyRow,
+{
+    pin_mut!(sink);
+
+    let binary = format == CopyFormat::Binary;
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE * 2);
+    if binary {
+        buf.put_slice(HEADER);
+    }
+    let mut tuple = BytesMut::new();
+    for row in rows {
+        if binary {
+            encode_row(row, &mut buf)?;
+        } else {
+            encode_row(row, &mut tuple)?;
+            format.write_tuples(T::TYPES, &tuple.split(), &mut buf)?;
+        }
+        if buf.len() > CHUNK_SIZE {
+            sink.send(buf.split().freeze()).await?;
+        }
+    }
+    if !encoded.is_empty() {
+        if binary {
+            sink.send(buf.split().freeze()).await?;
+            sink.send(encoded).await?;
+        } else {
+            format.write_tuples(T::TYPES, &encoded, &mut buf)?;
+        }
+    }
+    if binary {
+        buf.put_slice(TRAILER);
+    }
+    if !buf.is_empty() {
+        sink.send(buf.freeze()).await?;
+    }
+    Ok(sink.finish().await?)
+}
+
+/// COPY the rows of each routed table in turn
+async fn copy_routes<T>(
+    client: &Client,
+    routes: &[Route<T>],
+    format: CopyFormat,
+) -> Result<u64, Box<dyn Error + Sync + Send>>
+where
+    T: BatchCopyRow,
+{
+    let mut nrows = 0;
+    for route in routes {
+        let sink = client
+            .copy_in(&format.copy_statement(&route.copy_statement))
+            .await?;
+        nrows += copy_rows(sink, &route.rows, route.encoded.clone(), format).await?;
+    }
+    Ok(nrows)
+}
@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::ops::{Bound, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+use bytes::{BufMut, BytesMut};
+use tokio_postgres::types::{to_sql_checked, IsNull, Kind, ToSql, Type};
+
+// Flag bits from postgres' rangetypes.h
+const RANGE_EMPTY: u8 = 0x01;
+const RANGE_LB_INC: u8 = 0x02;
+const RANGE_UB_INC: u8 = 0x04;
+const RANGE_LB_INF: u8 = 0x08;
+const RANGE_UB_INF: u8 = 0x10;
+
+/// A PostgreSQL range value, e.g. for INT8RANGE or TSTZRANGE columns.
+///
+/// Convert from the standard range expressions: `(a..b).into()` is `[a,b)`,
+/// `(a..=b).into()` is `[a,b]` and open ends are unbounded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgRange<T> {
+    lower: Bound<T>,
+    upper: Bound<T>,
+    empty: bool,
+}
+
+impl<T> PgRange<T> {
+    pub fn new(lower: Bound<T>, upper: Bound<T>) -> Self {
+        Self {
+            lower,
+            upper,
+            empty: false,
+        }
+    }
+
+    /// The range containing no values, `'empty'`
+    pub fn empty() -> Self {
+        Self {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+            empty: true,
+        }
+    }
+
+    pub fn lower(&self) -> Bound<&T> {
+        self.lower.as_ref()
+    }
+
+    pub fn upper(&self) -> Bound<&T> {
+        self.upper.as_ref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.empty
+    }
+}
+
+impl<T> From<Range<T>> for PgRange<T> {
+    fn from(r: Range<T>) -> Self {
+        Self::new(Bound::Included(r.start), Bound::Excluded(r.end))
+    }
+}
+
+impl<T> From<RangeInclusive<T>> for PgRange<T> {
+    fn from(r: RangeInclusive<T>) -> Self {
+        let (start, end) = r.into_inner();
+        Self::new(Bound::Included(start), Bound::Included(end))
+    }
+}
+
+impl<T> From<RangeFrom<T>> for PgRange<T> {
+    fn from(r: RangeFrom<T>) -> Self {
+        Self::new(Bound::Included(r.start), Bound::Unbounded)
+    }
+}
+
+impl<T> From<RangeTo<T>> for PgRange<T> {
+    fn from(r: RangeTo<T>) -> Self {
+        Self::new(Bound::Unbounded, Bound::Excluded(r.end))
+    }
+}
+
+impl<T> From<RangeToInclusive<T>> for PgRange<T> {
+    fn from(r: RangeToInclusive<T>) -> Self {
+        Self::new(Bound::Unbounded, Bound::Included(r.end))
+    }
+}
+
+impl<T> From<RangeFull> for PgRange<T> {
+    fn from(_: RangeFull) -> Self {
+        Self::new(Bound::Unbounded, Bound::Unbounded)
+    }
+}
+
+fn write_bound<T: ToSql>(
+    value: &T,
+    ty: &Type,
+    out: &mut BytesMut,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let idx = out.len();
+    out.put_i32(0);
+    let len = match value.to_sql(ty, out)? {
+        IsNull::Yes => return Err("range bounds cannot be NULL".into()),
+        IsNull::No => i32::try_from(out.len() - idx - 4)?,
+    };
+    out[idx..idx + 4].copy_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+impl<T> ToSql for PgRange<T>
+where
+    T: ToSql,
+{
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let element = match ty.kind() {
+            Kind::Range(element) => element,
+            _ => return Err(format!("{ty} is not a range type").into()),
+        };
+
+        if self.empty {
+            out.put_u8(RANGE_EMPTY);
+            return Ok(IsNull::No);
+        }
+
+        let mut flags = 0;
+        match self.lower {
+            Bound::Included(_) => flags |= RANGE_LB_INC,
+            Bound::Excluded(_) => {}
+            Bound::Unbounded => flags |= RANGE_LB_INF,
+        }
+        match self.upper {
+            Bound::Included(_) => flags |= RANGE_UB_INC,
+            Bound::Excluded(_) => {}
+            Bound::Unbounded => flags |= RANGE_UB_INF,
+        }
+        out.put_u8(flags);
+
+        if let Bound::Included(v) | Bound::Excluded(v) = &self.lower {
+            write_bound(v, element, out)?;
+        }
+        if let Bound::Included(v) | Bound::Excluded(v) = &self.upper {
+            write_bound(v, element, out)?;
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        match ty.kind() {
+            Kind::Range(element) => T::accepts(element),
+            _ => false,
+        }
+    }
+
+    to_sql_checked!();
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Client;
+
+use crate::columns::TableSchema;
+use crate::errors::BatchCopyDatabaseError;
+use crate::schema::validate_schema;
+use crate::sink::Batch;
+use crate::BatchCopyRow;
+
+/// The tables other than `T::TABLE` that rows choose with `BatchCopyRow::table`.
+///
+/// Each table is validated against the row's columns the first time a batch
+/// routes to it, and optionally created like `T::TABLE` beforehand.
+pub(crate) struct Routes {
+    schema: TableSchema,
+    columns: String,
+    create: bool,
+    /// COPY statement of each validated table
+    tables: HashMap<String, String>,
+}
+
+/// Rows of one routed table, ready to COPY
+pub(crate) struct Route<T> {
+    pub(crate) copy_statement: String,
+    pub(crate) rows: Vec<T>,
+    pub(crate) encoded: Bytes,
+}
+
+impl Routes {
+    pub(crate) fn new<T: BatchCopyRow>(create: bool) -> Self {
+        Self {
+            schema: TableSchema::of::<T>(),
+            columns: T::COLUMNS
+                .iter()
+                .map(|c| c.name)
+                .collect::<Vec<_>>()
+                .join(", "),
+            create,
+            tables: HashMap::new(),
+        }
+    }
+
+    /// Move the rows bound for other tables out of `batch.rows`, along with the
+    /// encoded tuples, into `batch.routed` grouped by table. Rows of a table that
+    /// cannot be used are logged and dropped from the batch, and their count returned.
+    ///
+    /// Any other error preparing a table, such as a lost connection, fails the
+    /// batch before it is changed, so that it can be retried.
+    pub(crate) async fn split<T: BatchCopyRow>(
+        &mut self,
+        client: &Client,
+        batch: &mut Batch<T>,
+    ) -> Result<usize, BatchCopyDatabaseError> {
+        let mut tables = HashMap::new();
+        let names = batch.rows.iter().map(|row| row.table());
+        for table in names.chain(batch.tables.keys().map(|t| t.into())) {
+            if table == self.schema.table || tables.contains_key(table.as_ref()) {
+                continue;
+            }
+            let copy_statement = match self.prepare(client, &table).await {
+                Ok(copy_statement) => Ok(copy_statement.to_string()),
+                Err(e) if is_unusable(&e) => Err(e),
+                Err(e) => return Err(e),
+            };
+            tables.insert(table.into_owned(), copy_statement);
+        }
+
+        let mut grouped: HashMap<String, Vec<T>> = HashMap::new();
+        let mut own = Vec::with_capacity(batch.rows.len());
+        for row in batch.rows.drain(..) {
+            let table = row.table();
+            if table == self.schema.table {
+                own.push(row);
+            } else {
+                let table = table.into_owned();
+                grouped.entry(table).or_default().push(row);
+            }
+        }
+        batch.rows = own;
+
+        let mut discarded = 0;
+        for (table, copy_statement) in tables {
+            let rows = grouped.remove(&table).unwrap_or_default();
+            let (encoded, encoded_rows) = batch.tables.remove(&table).unwrap_or_default();
+            match copy_statement {
+                Ok(copy_statement) => batch.routed.push(Route {
+                    copy_statement,
+                    rows,
+                    encoded,
+                }),
+                Err(e) => {
+                    let n = rows.len() + encoded_rows;
+                    log::error!(
+                        "Error preparing table {table}, data loss has occured! {n} rows discarded:\n\t{e}"
+                    );
+                    discarded += n;
+                }
+            }
+        }
+        batch.len -= discarded;
+        Ok(discarded)
+    }
+
+    /// The COPY statement of `table`, creating and validating it on first use
+    async fn prepare(
+        &mut self,
+        client: &Client,
+        table: &str,
+    ) -> Result<&str, BatchCopyDatabaseError> {
+        if !self.tables.contains_key(table) {
+            // the name is spliced into statements unquoted, as T::TABLE is
+            if !is_table_name(table) {
+                return Err(BatchCopyDatabaseError::InvalidTableName(table.to_string()));
+            }
+            if self.create {
+                client
+                    .batch_execute(&format!(
+                        "CREATE TABLE IF NOT EXISTS {table} (LIKE {} INCLUDING ALL)",
+                        self.schema.table
+                    ))
+                    .await?;
+            }
+            let check = format!("SELECT {} FROM {table} LIMIT 0", self.columns);
+            validate_schema(client, &self.schema, &check).await?;
+            log::info!("routing rows to {table}");
+            let copy = format!("COPY {table} ({}) FROM STDIN (FORMAT binary)", self.columns);
+            self.tables.insert(table.to_string(), copy);
+        }
+        Ok(&self.tables[table])
+    }
+}
+
+/// `[A-Za-z_][A-Za-z0-9_]*`, optionally qualified by a schema of the same form
+fn is_table_name(table: &str) -> bool {
+    let identifier = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    match table.split_once('.') {
+        Some((schema, name)) => identifier(schema) && identifier(name),
+        None => identifier(table),
+    }
+}
+
+/// Whether `e` rules a table out for good: an invalid name, a table that does
+/// not exist, or one whose columns cannot take the rows
+fn is_unusable(e: &BatchCopyDatabaseError) -> bool {
+    match e {
+        BatchCopyDatabaseError::InvalidTableName(_)
+        | BatchCopyDatabaseError::SchemaMismatch { .. } => true,
+        BatchCopyDatabaseError::SchemaCheckFailed { source, .. } => {
+            source.code() == Some(&SqlState::UNDEFINED_TABLE)
+        }
+        _ => false,
+    }
+}
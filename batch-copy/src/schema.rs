@@ -0,0 +1,312 @@
+use tokio_postgres::types::{Kind, Type};
+use tokio_postgres::GenericClient;
+
+use crate::columns::TableSchema;
+use crate::errors::{BatchCopyDatabaseError, ColumnMismatch};
+use crate::BatchCopyRow;
+
+const NOT_NULL_QUERY: &str =
+    "SELECT attnum, attnotnull FROM pg_attribute WHERE attrelid = $1 AND attnum = ANY($2)";
+
+/// Name, type, nullability and whether the type is an enum of each column, with
+/// domains resolved to their base type, since that is what binary COPY writes
+const COLUMNS_QUERY: &str = "WITH RECURSIVE cols AS (
+        SELECT a.attnum, a.attname, a.attnotnull, a.atttypid AS typid
+        FROM pg_attribute a
+        JOIN pg_class c ON c.oid = a.attrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = COALESCE($1::text, current_schema()::text) AND c.relname = $2::text
+            AND a.attnum > 0 AND NOT a.attisdropped
+        UNION ALL
+        SELECT cols.attnum, cols.attname, cols.attnotnull, t.typbasetype
+        FROM cols JOIN pg_type t ON t.oid = cols.typid
+        WHERE t.typtype = 'd'
+    )
+    SELECT cols.attname::text, t.typname::text, NOT cols.attnotnull, t.typtype = 'e'
+    FROM cols JOIN pg_type t ON t.oid = cols.typid
+    WHERE t.typtype <> 'd'
+    ORDER BY cols.attnum";
+
+/// Binary COPY writes these interchangeably
+const TEXT_TYPES: &[&str] = &["text", "varchar", "bpchar", "name"];
+
+/// A statement that brings the live table in line with a row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    /// the table does not exist
+    CreateTable { sql: String },
+    /// the row has a column the table lacks
+    AddColumn { column: String, sql: String },
+    /// the column's type cannot take the row's values
+    AlterType { column: String, sql: String },
+    /// the column is NOT NULL but the row field is optional
+    DropNotNull { column: String, sql: String },
+}
+
+impl SchemaChange {
+    pub fn sql(&self) -> &str {
+        match self {
+            Self::CreateTable { sql }
+            | Self::AddColumn { sql, .. }
+            | Self::AlterType { sql, .. }
+            | Self::DropNotNull { sql, .. } => sql,
+        }
+    }
+
+    /// Additive changes only create tables or columns, leaving existing data as it is
+    pub fn is_additive(&self) -> bool {
+        matches!(self, Self::CreateTable { .. } | Self::AddColumn { .. })
+    }
+}
+
+/// The changes needed to reconcile a table with a row, see [`schema_diff`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    pub changes: Vec<SchemaChange>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// All statements, in the order they should be run
+    pub fn statements(&self) -> impl Iterator<Item = &str> {
+        self.changes.iter().map(SchemaChange::sql)
+    }
+
+    /// Run the additive changes in one transaction, returning how many were applied.
+    /// Inside a caller's `Transaction` they run in a savepoint instead.
+    ///
+    /// Type and nullability changes are left for a human to review.
+    pub async fn apply_additive<C>(&self, client: &mut C) -> Result<usize, BatchCopyDatabaseError>
+    where
+        C: GenericClient,
+    {
+        let additive: Vec<&str> = self
+            .changes
+            .iter()
+            .filter(|c| c.is_additive())
+            .map(SchemaChange::sql)
+            .collect();
+        if !additive.is_empty() {
+            let transaction = client.transaction().await?;
+            for sql in &additive {
+                transaction.batch_execute(sql).await?;
+            }
+            transaction.commit().await?;
+        }
+        Ok(additive.len())
+    }
+}
+
+/// Compare the live table with `T` through the catalog, a domain column by its base type.
+///
+/// Added columns are nullable so that `ADD COLUMN` succeeds on a populated table.
+/// Table columns that `T` does not write are ignored.
+pub async fn schema_diff<T, C>(client: &C) -> Result<SchemaDiff, BatchCopyDatabaseError>
+where
+    T: BatchCopyRow + ?Sized,
+    C: GenericClient,
+{
+    // unquoted identifiers are folded to lower case, as postgres does
+    let table = T::TABLE.to_lowercase();
+    let (schema, name) = match table.split_once('.') {
+        Some((schema, name)) => (Some(schema), name),
+        None => (None, table.as_str()),
+    };
+    let existing = client.query(COLUMNS_QUERY, &[&schema, &name]).await?;
+
+    let mut changes = Vec::new();
+    if existing.is_empty() {
+        changes.push(SchemaChange::CreateTable {
+            sql: T::DDL_STATEMENT.trim_end_matches(';').to_string(),
+        });
+        return Ok(SchemaDiff { changes });
+    }
+
+    for (col, row_type) in T::COLUMNS.iter().zip(T::TYPES) {
+        let table_col = existing
+            .iter()
+            .find(|r| r.get::<_, &str>(0) == col.name.to_lowercase());
+        let Some(table_col) = table_col else {
+            changes.push(SchemaChange::AddColumn {
+                column: col.name.to_string(),
+                sql: format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    T::TABLE,
+                    col.name,
+                    col.sql_type
+                ),
+            });
+            continue;
+        };
+
+        if !binary_compatible(table_col.get(1), table_col.get(3), row_type) {
+            changes.push(SchemaChange::AlterType {
+                column: col.name.to_string(),
+                sql: format!(
+                    "ALTER TABLE {} ALTER COLUMN {} TYPE {} USING {}::{}",
+                    T::TABLE,
+                    col.name,
+                    col.sql_type,
+                    col.name,
+                    col.sql_type
+                ),
+            });
+        }
+        if col.nullable && !table_col.get::<_, bool>(2) {
+            changes.push(SchemaChange::DropNotNull {
+                column: col.name.to_string(),
+                sql: format!(
+                    "ALTER TABLE {} ALTER COLUMN {} DROP NOT NULL",
+                    T::TABLE,
+                    col.name
+                ),
+            });
+        }
+    }
+    Ok(SchemaDiff { changes })
+}
+
+/// Run `T::DDL_STATEMENT` as `CREATE TABLE IF NOT EXISTS`.
+///
+/// An existing table is left untouched, even if its columns differ from `T`;
+/// follow up with [`validate`] to find out. Its indexes and comments are only
+/// created along with the table.
+pub async fn create_table<T, C>(client: &C) -> Result<(), BatchCopyDatabaseError>
+where
+    T: BatchCopyRow + ?Sized,
+    C: GenericClient,
+{
+    create_table_schema(client, &TableSchema::of::<T>()).await
+}
+
+pub(crate) async fn create_table_schema<C>(
+    client: &C,
+    schema: &TableSchema,
+) -> Result<(), BatchCopyDatabaseError>
+where
+    C: GenericClient,
+{
+    create_table_ddl(client, schema.table, schema.ddl_statement).await
+}
+
+/// Run `ddl_statement`, which creates `table` and then its indexes and comments,
+/// unless `table` already exists
+pub(crate) async fn create_table_ddl<C>(
+    client: &C,
+    table: &str,
+    ddl_statement: &str,
+) -> Result<(), BatchCopyDatabaseError>
+where
+    C: GenericClient,
+{
+    let exists: bool = client
+        .query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])
+        .await?
+        .get(0);
+    if exists {
+        return Ok(());
+    }
+
+    let ddl = match ddl_statement.strip_prefix("CREATE TABLE ") {
+        Some(rest) => format!("CREATE TABLE IF NOT EXISTS {rest}"),
+        None => ddl_statement.to_string(),
+    };
+    client.batch_execute(&ddl).await?;
+    Ok(())
+}
+
+/// Check that the live table can accept `T`'s rows over binary COPY.
+///
+/// `T::CHECK_STATEMENT` is prepared rather than executed, so the server reports
+/// the type of every column. Each type must be binary compatible with the type
+/// the row writes, and columns declared `NOT NULL` must not come from `Option`
+/// fields. All mismatches are reported together.
+pub async fn validate<T, C>(client: &C) -> Result<(), BatchCopyDatabaseError>
+where
+    T: BatchCopyRow + ?Sized,
+    C: GenericClient,
+{
+    let schema = TableSchema::of::<T>();
+    validate_schema(client, &schema, schema.check_statement).await
+}
+
+/// Validate the table read by `check_statement`, which may be another table
+/// with the same columns as `schema`
+pub(crate) async fn validate_schema<C>(
+    client: &C,
+    schema: &TableSchema,
+    check_statement: &str,
+) -> Result<(), BatchCopyDatabaseError>
+where
+    C: GenericClient,
+{
+    let stmt = client.prepare(check_statement).await.map_err(|e| {
+        BatchCopyDatabaseError::SchemaCheckFailed {
+            source: e,
+            ddl: schema.ddl_statement.to_string(),
+        }
+    })?;
+
+    let mut mismatches = Vec::new();
+    let mut attnums = Vec::new();
+    for ((table_col, row_type), row_col) in
+        stmt.columns().iter().zip(schema.types).zip(schema.columns)
+    {
+        let table_type = table_col.type_();
+        let table_enum = matches!(table_type.kind(), Kind::Enum(_));
+        if !binary_compatible(table_type.name(), table_enum, row_type) {
+            mismatches.push(ColumnMismatch::Type {
+                column: row_col.name.to_string(),
+                table_type: table_col.type_().to_string(),
+                row_type: row_col.sql_type.to_string(),
+            });
+        }
+        if row_col.nullable {
+            if let Some(attnum) = table_col.column_id() {
+                attnums.push(attnum);
+            }
+        }
+    }
+
+    // every column comes from the same table, so the first one names it
+    let table_oid = stmt.columns().first().and_then(|c| c.table_oid());
+    if let (Some(oid), false) = (table_oid, attnums.is_empty()) {
+        for row in client.query(NOT_NULL_QUERY, &[&oid, &attnums]).await? {
+            let attnum: i16 = row.get(0);
+            if !row.get::<_, bool>(1) {
+                continue;
+            }
+            let idx = stmt
+                .columns()
+                .iter()
+                .position(|c| c.column_id() == Some(attnum));
+            if let Some(col) = idx.and_then(|i| schema.columns.get(i)) {
+                mismatches.push(ColumnMismatch::Nullability {
+                    column: col.name.to_string(),
+                });
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(BatchCopyDatabaseError::SchemaMismatch {
+            mismatches,
+            ddl: schema.ddl_statement.to_string(),
+        })
+    }
+}
+
+/// Whether a value encoded as `row` is accepted by the binary input function of
+/// the column type named `table`, an enum if `table_enum`.
+///
+/// Domains need no special case: the server describes them by their base type.
+fn binary_compatible(table: &str, table_enum: bool, row: &Type) -> bool {
+    let text_row = TEXT_TYPES.contains(&row.name());
+
+    table == row.name() || (text_row && (TEXT_TYPES.contains(&table) || table_enum))
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use tokio_postgres::Client;
+
+/// Per-producer high-water marks for `Copier::send_sequenced`, kept by the actor.
+///
+/// Marks are written to the bookkeeping table in the same transaction as the
+/// rows they cover, so after a restart a replayed sequence is skipped exactly
+/// when its row was committed.
+pub(crate) struct Sequences {
+    committed: HashMap<String, i64>,
+    /// highest sequence of each producer in the buffered rows
+    pending: HashMap<String, i64>,
+}
+
+/// The bookkeeping table the marks are written to, kept by the sink
+pub(crate) struct SequenceTable {
+    /// shared by every target table
+    table: String,
+    /// the table the rows are copied to
+    target: &'static str,
+}
+
+impl SequenceTable {
+    /// Create the bookkeeping table if needed and read the committed marks for `target`
+    pub(crate) async fn load(
+        client: &Client,
+        table: String,
+        target: &'static str,
+    ) -> Result<(Self, Sequences), tokio_postgres::Error> {
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    target_table TEXT NOT NULL,
+                    producer TEXT NOT NULL,
+                    seq BIGINT NOT NULL,
+                    PRIMARY KEY (target_table, producer)
+                )"
+            ))
+            .await?;
+        let committed = client
+            .query(
+                &format!("SELECT producer, seq FROM {table} WHERE target_table = $1"),
+                &[&target],
+            )
+            .await?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        let sequences = Sequences {
+            committed,
+            pending: HashMap::new(),
+        };
+        Ok((Self { table, target }, sequences))
+    }
+
+    /// Write the marks of a batch inside its transaction
+    pub(crate) async fn record(
+        &self,
+        client: &Client,
+        marks: &HashMap<String, i64>,
+    ) -> Result<(), tokio_postgres::Error> {
+        if marks.is_empty() {
+            return Ok(());
+        }
+        let (producers, seqs): (Vec<&str>, Vec<i64>) =
+            marks.iter().map(|(p, s)| (p.as_str(), *s)).unzip();
+        let sql = format!(
+            "INSERT INTO {table} (target_table, producer, seq)
+             SELECT $1, * FROM unnest($2::text[], $3::int8[])
+             ON CONFLICT (target_table, producer) DO UPDATE SET seq = GREATEST({table}.seq, EXCLUDED.seq)",
+            table = self.table
+        );
+        client
+            .execute(sql.as_str(), &[&self.target, &producers, &seqs])
+            .await?;
+        Ok(())
+    }
+}
+
+impl Sequences {
+    /// Whether `seq` is newer than anything buffered or committed for `producer`
+    pub(crate) fn accept(&mut self, producer: &str, seq: i64) -> bool {
+        let high = self
+            .pending
+            .get(producer)
+            .or_else(|| self.committed.get(producer));
+        if high.is_some_and(|high| seq <= *high) {
+            return false;
+        }
+        self.pending.insert(producer.to_string(), seq);
+        true
+    }
+
+    /// Take the marks of the batch about to be flushed; they only count once
+    /// passed back to `committed`
+    pub(crate) fn take_pending(&mut self) -> HashMap<String, i64> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The batch with these marks was committed
+    pub(crate) fn committed(&mut self, marks: HashMap<String, i64>) {
+        self.committed.extend(marks);
+    }
+}
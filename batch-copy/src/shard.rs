@@ -0,0 +1,147 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use futures_util::future::join_all;
+use tokio_postgres::types::{IsNull, ToSql};
+
+use crate::errors::BatchCopyDatabaseError;
+use crate::handler::{Configuration, Copier};
+use crate::stats::CopyStats;
+use crate::BatchCopyRow;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A copier per database, with each row sent to the shard its key hashes to.
+///
+/// Every shard has its own actor, pool and batches, so a failed batch on one
+/// shard does not affect the others.
+pub struct ShardedCopier<T>
+where
+    T: BatchCopyRow + Send,
+{
+    shards: Vec<Copier<T>>,
+    key: ShardKey<T>,
+}
+
+enum ShardKey<T> {
+    /// position of the `#[batch_copy(shard_key)]` column
+    Column(usize),
+    Fn(Arc<dyn Fn(&T) -> u64 + Send + Sync>),
+}
+
+impl<T> Clone for ShardKey<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Column(column) => Self::Column(*column),
+            Self::Fn(f) => Self::Fn(f.clone()),
+        }
+    }
+}
+
+impl<T> Clone for ShardedCopier<T>
+where
+    T: BatchCopyRow + Send + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+impl<T> ShardedCopier<T>
+where
+    T: BatchCopyRow + Send + Sync + Clone + Debug + 'static,
+{
+    /// One shard per configuration, in order, keyed by the `#[batch_copy(shard_key)]` field.
+    ///
+    /// A row goes to the shard at the FNV-1a hash of the key's binary encoding,
+    /// modulo the number of shards; a NULL key goes to the first shard.
+    pub async fn new(configs: Vec<Configuration>) -> Result<Self, BatchCopyDatabaseError> {
+        let column = T::SHARD_COLUMN
+            .and_then(|name| T::COLUMNS.iter().position(|c| c.name == name))
+            .ok_or(BatchCopyDatabaseError::InvalidConfiguration(
+                "ShardedCopier::new needs a #[batch_copy(shard_key)] field, see ShardedCopier::with_shard_fn",
+            ))?;
+        Self::build(configs, ShardKey::Column(column)).await
+    }
+
+    /// One shard per configuration, in order, with rows going to the shard at
+    /// `shard_fn(&row)` modulo the number of shards.
+    pub async fn with_shard_fn<F>(
+        configs: Vec<Configuration>,
+        shard_fn: F,
+    ) -> Result<Self, BatchCopyDatabaseError>
+    where
+        F: Fn(&T) -> u64 + Send + Sync + 'static,
+    {
+        Self::build(configs, ShardKey::Fn(Arc::new(shard_fn))).await
+    }
+
+    async fn build(
+        configs: Vec<Configuration>,
+        key: ShardKey<T>,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        if configs.is_empty() {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "ShardedCopier needs at least one Configuration",
+            ));
+        }
+        let mut shards = Vec::with_capacity(configs.len());
+        for cfg in configs {
+            shards.push(Copier::new(cfg).await?);
+        }
+        Ok(Self { shards, key })
+    }
+
+    /// The index of the shard `row` is sent to
+    pub fn shard_of(&self, row: &T) -> usize {
+        let hash = match &self.key {
+            ShardKey::Column(column) => {
+                let mut values: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(T::TYPES.len());
+                row.fill_copy_refs(&mut values);
+                let mut buf = BytesMut::new();
+                match values[*column].to_sql_checked(&T::TYPES[*column], &mut buf) {
+                    Ok(IsNull::No) => fnv1a(&buf),
+                    _ => 0,
+                }
+            }
+            ShardKey::Fn(f) => f(row),
+        };
+        (hash % self.shards.len() as u64) as usize
+    }
+
+    /// The copier of each shard, in configuration order
+    pub fn shards(&self) -> &[Copier<T>] {
+        &self.shards
+    }
+
+    pub async fn send(&self, row: T) {
+        self.shards[self.shard_of(&row)].send(row).await;
+    }
+
+    /// Flush every shard concurrently
+    pub async fn flush(&self) {
+        join_all(self.shards.iter().map(Copier::flush)).await;
+    }
+
+    /// The counters of every shard added together
+    pub fn stats(&self) -> CopyStats {
+        self.shards.iter().map(Copier::stats).sum()
+    }
+
+    /// The counters of each shard, in configuration order
+    pub fn shard_stats(&self) -> Vec<CopyStats> {
+        self.shards.iter().map(Copier::stats).collect()
+    }
+}
+
+/// 64-bit FNV-1a, stable across platforms and releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(FNV_PRIME)
+    })
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+
+use bytes::Bytes;
+
+use crate::errors::BatchCopyDatabaseError;
+use crate::route::Route;
+
+/// Where the actor writes its batches.
+///
+/// For each flush the actor calls `begin`, then `write`, then `commit`, or `abort`
+/// if `write` fails. A batch that fails anywhere is offered to `retry`, and
+/// written again from `begin` while it returns true. Postgres COPY is the sink
+/// behind `Copier::new`; `Copier::with_sink` drives any other.
+pub trait BatchSink<T>: Send + 'static {
+    /// An open batch, such as a database transaction
+    type Transaction: Send;
+
+    /// Open a batch. Rows the sink cannot take may be removed from `batch`, and
+    /// are counted as discarded.
+    fn begin(
+        &mut self,
+        batch: &mut Batch<T>,
+    ) -> impl Future<Output = Result<Self::Transaction, Box<dyn Error + Sync + Send>>> + Send;
+
+    /// Write the rows of the batch, returning the number of rows written
+    fn write(
+        &mut self,
+        transaction: &mut Self::Transaction,
+        batch: &Batch<T>,
+    ) -> impl Future<Output = Result<u64, Box<dyn Error + Sync + Send>>> + Send;
+
+    /// Make the written rows durable
+    fn commit(
+        &mut self,
+        transaction: Self::Transaction,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Sync + Send>>> + Send;
+
+    /// Discard whatever `write` got through
+    fn abort(&mut self, transaction: Self::Transaction) -> impl Future<Output = ()> + Send;
+
+    /// Whether to write a batch again after it failed with `error`, having been
+    /// retried `attempt` times. Never, by default.
+    fn retry(
+        &mut self,
+        error: &(dyn Error + Sync + Send + 'static),
+        attempt: u32,
+    ) -> impl Future<Output = bool> + Send {
+        let _ = (error, attempt);
+        async { false }
+    }
+
+    /// Called once `Copier::flush` has written every buffered row. Does nothing
+    /// by default.
+    fn flushed(&mut self) {}
+
+    /// Publish everything written since the last swap, see `Copier::swap`
+    fn swap(&mut self) -> impl Future<Output = Result<(), BatchCopyDatabaseError>> + Send {
+        async {
+            Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "swap is not supported by this sink",
+            ))
+        }
+    }
+}
+
+/// The rows of one flush, kept until they are committed or given up on
+pub struct Batch<T> {
+    pub(crate) rows: Vec<T>,
+    pub(crate) encoded: Bytes,
+    /// tuples encoded for tables other than `T::TABLE`, or for each variant of a
+    /// `MultiCopier`, and their count
+    pub(crate) tables: HashMap<String, (Bytes, usize)>,
+    /// rows bound for other tables, split off by the first attempt
+    pub(crate) routed: Vec<Route<T>>,
+    pub(crate) marks: HashMap<String, i64>,
+    pub(crate) len: usize,
+}
+
+impl<T> Batch<T> {
+    /// Rows sent by value
+    pub fn rows(&self) -> &[T] {
+        &self.rows
+    }
+
+    /// Binary COPY tuples (without header or trailer) sent by `Copier::send_ref`
+    /// and `Copier::load_file` for `T::TABLE`
+    pub fn encoded(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    /// Binary COPY tuples sent by `Copier::send_ref` for each table other than
+    /// `T::TABLE`, for rows with a `table_fn`
+    pub fn encoded_tables(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.tables
+            .iter()
+            .map(|(table, (tuples, _))| (table.as_str(), &tuples[..]))
+    }
+
+    /// The number of rows in the batch, sent by value or encoded
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The highest sequence number of each producer in the batch, see `Copier::send_sequenced`
+    pub fn marks(&self) -> &HashMap<String, i64> {
+        &self.marks
+    }
+}
@@ -0,0 +1,74 @@
+use std::iter::Sum;
+use std::ops::{Add, AddAssign};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What a copier's actor has done since the copier was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    /// rows committed to the table
+    pub rows_copied: u64,
+    /// rows lost to a failed batch or to an encoding error
+    pub rows_discarded: u64,
+    /// committed batches
+    pub batches: u64,
+    /// batches rolled back
+    pub failed_batches: u64,
+}
+
+impl Add for CopyStats {
+    type Output = Self;
+
+    fn add(mut self, other: Self) -> Self {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for CopyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.rows_copied += other.rows_copied;
+        self.rows_discarded += other.rows_discarded;
+        self.batches += other.batches;
+        self.failed_batches += other.failed_batches;
+    }
+}
+
+impl Sum for CopyStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), Add::add)
+    }
+}
+
+/// The live counters behind `CopyStats`, shared by a copier and its actor
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    rows_copied: AtomicU64,
+    rows_discarded: AtomicU64,
+    batches: AtomicU64,
+    failed_batches: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn committed(&self, rows: u64) {
+        self.rows_copied.fetch_add(rows, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failed(&self, rows: u64) {
+        self.discarded(rows);
+        self.failed_batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn discarded(&self, rows: u64) {
+        self.rows_discarded.fetch_add(rows, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CopyStats {
+        CopyStats {
+            rows_copied: self.rows_copied.load(Ordering::Relaxed),
+            rows_discarded: self.rows_discarded.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+            failed_batches: self.failed_batches.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use tokio_postgres::types::{Kind, ToSql, Type};
+
+use crate::binary::encode_values;
+use crate::errors::BatchCopyDatabaseError;
+use crate::failover::Failover;
+use crate::format::CopyFormat;
+use crate::handler::{Configuration, Copier, Pool, PoolSettings};
+use crate::postgres::PostgresSink;
+use crate::stats::CopyStats;
+use crate::write_mode::{WriteMode, Writer};
+use crate::BatchCopyRow;
+
+/// A copier for a table known only at runtime, its columns and their types
+/// read from the database instead of a row struct.
+///
+/// Batching, flushing and failover work as for `Copier`.
+#[derive(Clone)]
+pub struct TableCopier {
+    copier: Copier<Encoded>,
+    columns: Arc<[(String, Type)]>,
+}
+
+/// Stands in for a row type, rows of copiers without one only reach the actor
+/// already encoded
+#[derive(Debug, Clone)]
+pub(crate) enum Encoded {}
+
+impl BatchCopyRow for Encoded {
+    const TABLE: &'static str = "";
+    const TYPES: &'static [Type] = &[];
+    const COLUMNS: &'static [crate::Column] = &[];
+    const COPY_STATEMENT: &'static str = "";
+    const CHECK_STATEMENT: &'static str = "";
+    const DDL_STATEMENT: &'static str = "";
+
+    fn fill_copy_refs<'a>(&'a self, _out: &mut Vec<&'a (dyn ToSql + Sync)>) {
+        match *self {}
+    }
+}
+
+impl TableCopier {
+    /// Read the types of `columns` of an existing `table`, every column but
+    /// generated ones in table order if `None`.
+    ///
+    /// Names are taken as written, case included. `table` may be qualified
+    /// with its schema as `schema.table`.
+    ///
+    /// Only binary `WriteMode::Copy` is supported, without partitions, sequences
+    /// or added columns.
+    pub async fn new(
+        cfg: Configuration,
+        table: &str,
+        columns: Option<&[&str]>,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        let pool_settings = plain_settings(&cfg)?;
+        let pool = pool_settings.build().await?;
+        let columns: Vec<(String, Type)> = {
+            let conn = pool
+                .get()
+                .await
+                .map_err(|_| BatchCopyDatabaseError::BadConnection)?;
+            let select = match columns {
+                Some(columns) => quote_columns(columns.iter().copied()),
+                None => {
+                    let rows = conn
+                        .query(
+                            "SELECT attname::text FROM pg_attribute
+                             WHERE attrelid = $1::text::regclass AND attnum > 0
+                               AND NOT attisdropped AND attgenerated = ''
+                             ORDER BY attnum",
+                            &[&quote_table(table)],
+                        )
+                        .await?;
+                    quote_columns(rows.iter().map(|row| row.get::<_, &str>(0)))
+                }
+            };
+            let stmt = conn
+                .prepare(&format!(
+                    "SELECT {select} FROM {} LIMIT 0",
+                    quote_table(table)
+                ))
+                .await?;
+            stmt.columns()
+                .iter()
+                .map(|c| (c.name().to_string(), base_type(c.type_())))
+                .collect()
+        };
+
+        let names = quote_columns(columns.iter().map(|(name, _)| name.as_str()));
+        let copy_statement = format!(
+            "COPY {} ({names}) FROM STDIN (FORMAT binary)",
+            quote_table(table)
+        );
+        Ok(Self {
+            copier: spawn_encoded(&cfg, pool, pool_settings, copy_statement),
+            columns: columns.into(),
+        })
+    }
+
+    /// The name and type of each column, in the order `send` takes their values.
+    /// A domain column has the type of its base, which is what COPY reads.
+    pub fn columns(&self) -> &[(String, Type)] {
+        &self.columns
+    }
+
+    /// Encode one row, a value for each column, and send it to the actor.
+    ///
+    /// A value of a Rust type its column cannot take rejects the row, reported
+    /// as row 0.
+    pub async fn send(&self, values: &[&(dyn ToSql + Sync)]) -> Result<(), BatchCopyDatabaseError> {
+        if values.len() != self.columns.len() {
+            return Err(BatchCopyDatabaseError::InvalidValue {
+                column: String::new(),
+                row: 0,
+                reason: format!(
+                    "expected {} values but got {}",
+                    self.columns.len(),
+                    values.len()
+                ),
+            });
+        }
+        let mut buf = BytesMut::new();
+        let types = self.columns.iter().map(|(_, ty)| ty);
+        encode_values(values, types, &mut buf).map_err(|(idx, e)| {
+            BatchCopyDatabaseError::InvalidValue {
+                column: self.columns[idx].0.clone(),
+                row: 0,
+                reason: e.to_string(),
+            }
+        })?;
+        self.copier.send_tuples(buf.freeze(), 1, None).await;
+        Ok(())
+    }
+
+    pub async fn flush(&self) {
+        self.copier.flush().await;
+    }
+
+    /// Rows and batches the actor has copied or lost so far
+    pub fn stats(&self) -> CopyStats {
+        self.copier.stats()
+    }
+}
+
+fn base_type(ty: &Type) -> Type {
+    match ty.kind() {
+        Kind::Domain(base) => base_type(base),
+        _ => ty.clone(),
+    }
+}
+
+/// Quote `name` as a Postgres identifier, so it keeps its case and cannot end
+/// the statement it is put in
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quote a table name, each part of `schema.table` on its own
+pub(crate) fn quote_table(table: &str) -> String {
+    match table.split_once('.') {
+        Some((schema, table)) => format!("{}.{}", quote_ident(schema), quote_ident(table)),
+        None => quote_ident(table),
+    }
+}
+
+/// Quote and join column names for a column list
+pub(crate) fn quote_columns<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    names.map(quote_ident).collect::<Vec<_>>().join(", ")
+}
+
+/// Pool settings for a copier without a row type, which only supports binary
+/// `WriteMode::Copy` without partitions, sequences or added columns
+pub(crate) fn plain_settings(cfg: &Configuration) -> Result<PoolSettings, BatchCopyDatabaseError> {
+    if cfg.write_mode != WriteMode::Copy
+        || cfg.partition_interval.is_some()
+        || cfg.sequence_table.is_some()
+        || cfg.add_missing_columns
+        || cfg.copy_format.is_some_and(|f| f != CopyFormat::Binary)
+    {
+        return Err(BatchCopyDatabaseError::InvalidConfiguration(
+            "copiers without a row type only support binary WriteMode::Copy without partitions, sequences or added columns",
+        ));
+    }
+    PoolSettings::new(cfg)
+}
+
+/// Spawn the actor of a copier without a row type, copying its tuples with `copy_statement`
+pub(crate) fn spawn_encoded(
+    cfg: &Configuration,
+    pool: Pool,
+    pool_settings: PoolSettings,
+    copy_statement: String,
+) -> Copier<Encoded> {
+    let sink = PostgresSink {
+        pool,
+        partitioner: None,
+        writer: Writer::Table(copy_statement),
+        sequences: None,
+        routes: None,
+        format: CopyFormat::Binary,
+        failover: Failover::new(pool_settings, cfg),
+    };
+    Copier::spawn(
+        sink,
+        None,
+        cfg.max_rows_per_batch,
+        cfg.max_channel_capacity,
+        cfg.flush_timer_ms,
+    )
+}
@@ -0,0 +1,376 @@
+use std::collections::HashSet;
+
+use tokio_postgres::Client;
+
+use crate::errors::BatchCopyDatabaseError;
+use crate::table::quote_ident;
+use crate::{BatchCopyRow, Column};
+
+/// Longest identifier Postgres keeps, `NAMEDATALEN - 1`; longer ones are truncated
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Objects outside the table that `DROP TABLE` would refuse to drop it under,
+/// such as views and foreign keys of other tables
+const DEPENDENTS_QUERY: &str = "SELECT DISTINCT pg_describe_object(d.classid, d.objid, d.objsubid)
+    FROM pg_depend d
+    WHERE d.refclassid = 'pg_class'::regclass AND d.refobjid = $1::text::regclass AND d.deptype = 'n'
+        AND NOT EXISTS (SELECT 1 FROM pg_constraint c
+                        WHERE d.classid = 'pg_constraint'::regclass AND c.oid = d.objid
+                            AND c.conrelid = d.refobjid)
+    ORDER BY 1";
+
+/// How each batch is written to the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// COPY straight into the table; a duplicate key aborts the batch
+    #[default]
+    Copy,
+    /// Insert through a staging table, `ON CONFLICT (key) DO UPDATE` the update columns
+    Upsert,
+    /// Insert through a staging table, `ON CONFLICT (key) DO NOTHING`
+    SkipConflicts,
+    /// Delete the rows sharing a `replace_key` with the batch, then insert the batch.
+    /// Each key is replaced once until the next `Copier::flush`, so later batches
+    /// with that key append until then.
+    Replace,
+    /// COPY into a shadow table that `Copier::swap` renames over the table
+    Snapshot,
+}
+
+/// Where the actor COPYs a batch, and what happens to it before commit
+pub(crate) enum Writer {
+    /// COPY straight into the table with this statement
+    Table(String),
+    Staged(Staging),
+    Snapshot(Snapshot),
+}
+
+/// Statements for a write mode that COPYs into a temporary staging table first.
+///
+/// The staging table lives as long as the pooled connection and is emptied on
+/// commit, so it is only created once per connection.
+pub(crate) struct Staging {
+    /// create the staging table if this connection has none yet
+    create: String,
+    /// COPY into the staging table
+    copy: String,
+    merge: Merge,
+}
+
+/// Moves the staged rows into the table
+enum Merge {
+    /// `INSERT ... ON CONFLICT`
+    Insert {
+        sql: String,
+        /// plan the merge from the table itself, which fails unless a unique
+        /// index matches the conflict target
+        check: String,
+        /// a unique index for the conflict target
+        index_hint: String,
+    },
+    /// `DELETE` the rows with the batch's keys, then `INSERT`
+    Replace {
+        /// the batch's keys, each as the text of a row value
+        keys: String,
+        delete: String,
+        insert: String,
+        /// keys replaced by batches committed since the last `Copier::flush`
+        replaced: HashSet<String>,
+        /// keys replaced by the batch in flight
+        pending: Vec<String>,
+    },
+}
+
+pub(crate) struct Snapshot {
+    table: &'static str,
+    shadow: String,
+    copy: String,
+    /// create the empty shadow table, failing if another snapshot has one
+    create: String,
+    /// rename the shadow table over the table and drop the old one
+    swap: String,
+    /// the shadow table exists; after a swap, the next batch creates it again
+    created: bool,
+}
+
+fn names<'a>(columns: impl Iterator<Item = &'a Column>) -> Vec<&'static str> {
+    columns.map(|c| c.name).collect()
+}
+
+impl Writer {
+    pub(crate) fn new<T: BatchCopyRow>(mode: WriteMode) -> Result<Self, BatchCopyDatabaseError> {
+        let columns = names(T::COLUMNS.iter()).join(", ");
+        let stage = format!("batch_copy_stage_{}", T::TABLE.replace('.', "_"));
+        let create = format!(
+            "CREATE TEMP TABLE IF NOT EXISTS {stage} ON COMMIT DELETE ROWS AS {}",
+            T::CHECK_STATEMENT
+        );
+        let copy = format!("COPY {stage} ({columns}) FROM STDIN (FORMAT binary)");
+
+        let merge = match mode {
+            WriteMode::Copy => return Ok(Self::Table(T::COPY_STATEMENT.to_string())),
+            WriteMode::Snapshot => return Ok(Self::Snapshot(Snapshot::new::<T>(&columns)?)),
+            WriteMode::Upsert | WriteMode::SkipConflicts => {
+                Merge::upsert::<T>(mode, &stage, &columns)?
+            }
+            WriteMode::Replace => Merge::replace::<T>(&stage, &columns)?,
+        };
+        Ok(Self::Staged(Staging {
+            create,
+            copy,
+            merge,
+        }))
+    }
+
+    /// Fail fast if the table cannot take this write mode, and create the shadow
+    /// table of a snapshot
+    pub(crate) async fn prepare(&mut self, client: &Client) -> Result<(), BatchCopyDatabaseError> {
+        match self {
+            Self::Staged(Staging {
+                merge: Merge::Insert {
+                    check, index_hint, ..
+                },
+                ..
+            }) => client.batch_execute(check).await.map_err(|e| {
+                BatchCopyDatabaseError::SchemaCheckFailed {
+                    source: e,
+                    ddl: index_hint.clone(),
+                }
+            }),
+            // an existing shadow table belongs to a snapshot in progress, or to
+            // one that stopped before its swap, and is left for its owner
+            Self::Snapshot(snapshot) => {
+                client.batch_execute(&snapshot.create).await.map_err(|e| {
+                    BatchCopyDatabaseError::SchemaCheckFailed {
+                        source: e,
+                        ddl: format!("DROP TABLE {};", snapshot.shadow),
+                    }
+                })?;
+                snapshot.created = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the transaction ready and return the COPY statement for the batch
+    pub(crate) async fn begin<'a>(
+        &'a self,
+        client: &Client,
+    ) -> Result<&'a str, tokio_postgres::Error> {
+        match self {
+            Self::Table(copy) => Ok(copy),
+            Self::Staged(staging) => {
+                client.batch_execute(&staging.create).await?;
+                Ok(&staging.copy)
+            }
+            Self::Snapshot(snapshot) => {
+                if !snapshot.created {
+                    client.batch_execute(&snapshot.create).await?;
+                }
+                Ok(&snapshot.copy)
+            }
+        }
+    }
+
+    /// Move a copied batch into the table, returning the rows written if that
+    /// differs from the rows copied
+    pub(crate) async fn finish(
+        &mut self,
+        client: &Client,
+    ) -> Result<Option<u64>, tokio_postgres::Error> {
+        let Self::Staged(staging) = self else {
+            return Ok(None);
+        };
+        match &mut staging.merge {
+            Merge::Insert { sql, .. } => Ok(Some(client.execute(sql.as_str(), &[]).await?)),
+            Merge::Replace {
+                keys,
+                delete,
+                insert,
+                replaced,
+                pending,
+            } => {
+                *pending = client
+                    .query(keys.as_str(), &[])
+                    .await?
+                    .iter()
+                    .map(|row| row.get::<_, String>(0))
+                    .filter(|key| !replaced.contains(key))
+                    .collect();
+                if !pending.is_empty() {
+                    client.execute(delete.as_str(), &[&*pending]).await?;
+                }
+                Ok(Some(client.execute(insert.as_str(), &[]).await?))
+            }
+        }
+    }
+
+    /// The batch passed to `finish` was committed
+    pub(crate) fn committed(&mut self) {
+        match self {
+            Self::Staged(Staging {
+                merge: Merge::Replace {
+                    replaced, pending, ..
+                },
+                ..
+            }) => replaced.extend(pending.drain(..)),
+            Self::Snapshot(snapshot) => snapshot.created = true,
+            _ => {}
+        }
+    }
+
+    /// `Copier::flush` wrote every buffered row, so keys sent after it are
+    /// replaced again
+    pub(crate) fn flushed(&mut self) {
+        if let Self::Staged(Staging {
+            merge: Merge::Replace { replaced, .. },
+            ..
+        }) = self
+        {
+            replaced.clear();
+        }
+    }
+
+    /// Replace the table with the shadow table in one transaction
+    pub(crate) async fn swap(&mut self, client: &mut Client) -> Result<(), BatchCopyDatabaseError> {
+        let Self::Snapshot(snapshot) = self else {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "swap requires WriteMode::Snapshot",
+            ));
+        };
+        let transaction = client.transaction().await?;
+        // the old table is dropped after the rename, which its dependents would block
+        let dependents: Vec<String> = transaction
+            .query(DEPENDENTS_QUERY, &[&snapshot.table])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if !dependents.is_empty() {
+            return Err(BatchCopyDatabaseError::SnapshotDependents {
+                table: snapshot.table.to_string(),
+                dependents,
+            });
+        }
+        // nothing was copied since the last swap, so the table is emptied
+        if !snapshot.created {
+            transaction.batch_execute(&snapshot.create).await?;
+        }
+        transaction.batch_execute(&snapshot.swap).await?;
+        transaction.commit().await?;
+        snapshot.created = false;
+        Ok(())
+    }
+}
+
+impl Merge {
+    fn upsert<T: BatchCopyRow>(
+        mode: WriteMode,
+        stage: &str,
+        columns: &str,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        // conflict_key columns, falling back to the primary key
+        let mut keys = names(T::COLUMNS.iter().filter(|c| c.conflict_key));
+        if keys.is_empty() {
+            keys = names(T::COLUMNS.iter().filter(|c| c.primary_key));
+        }
+        if keys.is_empty() {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "upserts need #[batch_copy(conflict_key)] or #[batch_copy(primary_key)] fields",
+            ));
+        }
+        let keys = keys.join(", ");
+
+        let updates: Vec<String> = T::COLUMNS
+            .iter()
+            .filter(|c| c.update && !c.conflict_key && !c.primary_key)
+            .map(|c| format!("{0} = EXCLUDED.{0}", c.name))
+            .collect();
+        let action = match mode {
+            WriteMode::Upsert if !updates.is_empty() => {
+                format!("DO UPDATE SET {}", updates.join(", "))
+            }
+            _ => "DO NOTHING".to_string(),
+        };
+
+        // a row can only be updated once per statement, so the last of several
+        // rows with the same key wins
+        let merge_from = |source: &str| {
+            format!(
+                "INSERT INTO {table} ({columns}) \
+                 SELECT DISTINCT ON ({keys}) {columns} FROM {source} ORDER BY {keys}, ctid DESC \
+                 ON CONFLICT ({keys}) {action}",
+                table = T::TABLE
+            )
+        };
+
+        Ok(Self::Insert {
+            sql: merge_from(stage),
+            check: format!("EXPLAIN {}", merge_from(T::TABLE)),
+            index_hint: format!("CREATE UNIQUE INDEX ON {} ({keys});", T::TABLE),
+        })
+    }
+
+    fn replace<T: BatchCopyRow>(
+        stage: &str,
+        columns: &str,
+    ) -> Result<Self, BatchCopyDatabaseError> {
+        let keys = names(T::COLUMNS.iter().filter(|c| c.replace_key));
+        if keys.is_empty() {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "WriteMode::Replace needs #[batch_copy(replace_key)] fields",
+            ));
+        }
+        let key_list = keys.join(", ");
+        let join = keys
+            .iter()
+            .map(|k| format!("t.{k} = s.{k}"))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+        let table = T::TABLE;
+
+        Ok(Self::Replace {
+            keys: format!("SELECT DISTINCT ROW({key_list})::text FROM {stage}"),
+            delete: format!(
+                "DELETE FROM {table} t USING \
+                 (SELECT DISTINCT {key_list} FROM {stage} WHERE ROW({key_list})::text = ANY($1)) s \
+                 WHERE {join}"
+            ),
+            insert: format!("INSERT INTO {table} ({columns}) SELECT {columns} FROM {stage}"),
+            replaced: HashSet::new(),
+            pending: vec![],
+        })
+    }
+}
+
+impl Snapshot {
+    fn new<T: BatchCopyRow>(columns: &str) -> Result<Self, BatchCopyDatabaseError> {
+        let table = T::TABLE;
+        // RENAME TO takes a name without the schema
+        let (schema, name) = match table.rsplit_once('.') {
+            Some((schema, name)) => (format!("{schema}."), name),
+            None => (String::new(), table),
+        };
+        // both suffixes are 9 bytes, and a truncated name could be another table's
+        if name.len() + "_snapshot".len() > MAX_IDENTIFIER_LEN {
+            return Err(BatchCopyDatabaseError::InvalidConfiguration(
+                "WriteMode::Snapshot needs a table name of at most 54 bytes",
+            ));
+        }
+        let shadow = format!("{table}_snapshot");
+        let replaced = quote_ident(&format!("{name}_replaced"));
+        Ok(Self {
+            table,
+            copy: format!("COPY {shadow} ({columns}) FROM STDIN (FORMAT binary)"),
+            create: format!("CREATE TABLE {shadow} (LIKE {table} INCLUDING ALL)"),
+            swap: format!(
+                "ALTER TABLE {table} RENAME TO {replaced};
+                 ALTER TABLE {shadow} RENAME TO {name};
+                 DROP TABLE {schema}{replaced};"
+            ),
+            shadow,
+            created: false,
+        })
+    }
+}
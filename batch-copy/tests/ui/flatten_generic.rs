@@ -0,0 +1,31 @@
+use batch_copy::BatchCopy;
+
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "inner")]
+struct Inner {
+    a: i64,
+}
+
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "outer")]
+struct Outer<T> {
+    b: i64,
+    #[batch_copy(flatten)]
+    inner: T,
+}
+
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "tags")]
+struct Tag<'a> {
+    tag: &'a str,
+}
+
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "borrowed")]
+struct Borrowed<'a> {
+    name: &'a str,
+    #[batch_copy(flatten)]
+    tag: Tag<'a>,
+}
+
+fn main() {}
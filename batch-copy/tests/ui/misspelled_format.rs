@@ -0,0 +1,10 @@
+use batch_copy::BatchCopy;
+
+// a misspelled `format` would otherwise fall back to binary
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "events", fromat = "csv")]
+struct Event {
+    name: String,
+}
+
+fn main() {}
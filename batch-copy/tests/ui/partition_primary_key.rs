@@ -0,0 +1,27 @@
+use batch_copy::BatchCopy;
+
+// Postgres rejects a primary key of a partitioned table without the partition column
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "events", partition_by = "range(dt)")]
+struct Event {
+    #[batch_copy(primary_key)]
+    id: i64,
+    dt: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, BatchCopy)]
+struct Key {
+    #[batch_copy(primary_key)]
+    id: i64,
+}
+
+// with flattened fields the key is checked when the constants are evaluated
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "nested_events", partition_by = "range(dt)")]
+struct NestedEvent {
+    #[batch_copy(flatten)]
+    key: Key,
+    dt: chrono::NaiveDateTime,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use batch_copy::BatchCopy;
+
+// a misspelled `partition_by` would otherwise drop the partitioning
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "events", partition = "range(dt)")]
+struct Event {
+    dt: chrono::NaiveDateTime,
+    name: String,
+}
+
+#[derive(Debug, Clone, BatchCopy)]
+#[batch_copy(table = "flagged", snapshot)]
+struct Flagged {
+    name: String,
+}
+
+fn main() {}